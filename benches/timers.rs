@@ -0,0 +1,41 @@
+//! Measures how scheduling, cancelling and running timers scale with the number of pending timers.
+//!
+//! Run with `cargo bench --bench timers`.
+
+use thin_main_loop::MainLoop;
+use std::time::{Duration, Instant};
+use std::cell::Cell;
+
+fn bench(n: u64) {
+    let ran = Cell::new(0);
+    let mut ml = MainLoop::new().unwrap();
+
+    let t = Instant::now();
+    let ids: Vec<_> = (0..n).map(|i| {
+        // Spread the deadlines out, so insertion is not always at the end
+        let d = Duration::from_millis(100 + (i * 7919) % 1000);
+        ml.call_after(d, || {}).unwrap()
+    }).collect();
+    let push = t.elapsed();
+
+    let t = Instant::now();
+    for id in ids.iter().step_by(2) { assert!(ml.cancel(*id)); }
+    let cancel = t.elapsed();
+
+    let t = Instant::now();
+    for i in 0..n {
+        ml.call_after(Duration::from_millis(i % 10), || { ran.set(ran.get() + 1) }).unwrap();
+    }
+    while ran.get() < n {
+        ml.run_one(true);
+    }
+    let run = t.elapsed();
+
+    println!("{:>7} timers: push {:>12?}, cancel half {:>12?}, run {:>12?}", n, push, cancel, run);
+}
+
+fn main() {
+    for n in &[1000, 10_000, 100_000] {
+        bench(*n);
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{CbKind, CbId, MainLoopError, IODirection, IOEvent, Priority};
+use crate::mainloop::{SendFnOnce, catch_panic};
+use crate::unix::{Fd, cvt_ml as cvt};
+use boxfnonce::SendBoxFnOnce;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::Arc;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+use std::{io, mem, ptr};
+
+// CbIds start at one, so zero is free to use for the eventfd.
+const WAKE_TOKEN: u64 = 0;
+
+fn dir_to_epoll(d: IODirection) -> u32 {
+    (match d {
+        IODirection::None => 0,
+        IODirection::Read => libc::EPOLLIN | libc::EPOLLPRI,
+        IODirection::Write => libc::EPOLLOUT,
+        IODirection::Both => libc::EPOLLIN | libc::EPOLLPRI | libc::EPOLLOUT,
+    }) as u32
+}
+
+fn epoll_to_event(events: u32) -> Result<IOEvent, std::io::Error> {
+    let has = |flag: libc::c_int| events & (flag as u32) != 0;
+    Ok(IOEvent {
+        readable: has(libc::EPOLLIN),
+        writable: has(libc::EPOLLOUT),
+        hangup: has(libc::EPOLLHUP),
+        error: has(libc::EPOLLERR),
+        priority: has(libc::EPOLLPRI),
+    })
+}
+
+fn duration_to_timespec(d: Duration) -> libc::timespec {
+    // A zero timespec disarms the timer, so make sure we fire at least once.
+    let d = if d == Duration::from_secs(0) { Duration::new(0, 1) } else { d };
+    libc::timespec { tv_sec: d.as_secs() as libc::time_t, tv_nsec: d.subsec_nanos() as libc::c_long }
+}
+
+struct Data<'a> {
+    kind: CbKind<'a>,
+    priority: Priority,
+    handle: Option<(RawFd, IODirection)>,
+    // Closing the timerfd also removes it from the epoll set.
+    timer: Option<Fd>,
+}
+
+struct TSender {
+    wake: Arc<Fd>,
+    sender: Sender<SendBoxFnOnce<'static, ()>>,
+}
+
+impl SendFnOnce for TSender {
+    fn send(&self, f: SendBoxFnOnce<'static, ()>) -> Result<(), MainLoopError> {
+        self.sender.send(f).map_err(|_| MainLoopError::ChannelClosed)?;
+        let one = 1u64;
+        cvt(unsafe { libc::write(self.wake.0, &one as *const _ as *const _, mem::size_of::<u64>()) as libc::c_int })?;
+        Ok(())
+    }
+}
+
+pub struct Backend<'a> {
+    epoll: Fd,
+    wake: Arc<Fd>,
+    recv: Receiver<SendBoxFnOnce<'static, ()>>,
+    cb_map: RefCell<HashMap<CbId, Data<'a>>>,
+    // Ordered by priority
+    asap: RefCell<VecDeque<(Priority, CbId)>>,
+    idle: RefCell<VecDeque<CbId>>,
+}
+
+impl<'a> Backend<'a> {
+    pub (crate) fn new() -> Result<(Self, Box<dyn SendFnOnce>), MainLoopError> {
+        let epoll = Fd(cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })?);
+        let wake = Fd(cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })?);
+        let mut ev = libc::epoll_event { events: libc::EPOLLIN as u32, u64: WAKE_TOKEN };
+        cvt(unsafe { libc::epoll_ctl(epoll.0, libc::EPOLL_CTL_ADD, wake.0, &mut ev) })?;
+
+        let (tx, rx) = channel();
+        let wake = Arc::new(wake);
+        let be = Backend {
+            epoll,
+            wake: wake.clone(),
+            recv: rx,
+            cb_map: Default::default(),
+            asap: Default::default(),
+            idle: Default::default(),
+        };
+        Ok((be, Box::new(TSender { wake, sender: tx })))
+    }
+
+    fn call_data(&self, cbid: CbId, events: Option<u32>) -> bool {
+        let data = self.cb_map.borrow_mut().remove(&cbid);
+        let mut data = match data { Some(data) => data, None => return false };
+        if let Some(timer) = &data.timer {
+            // Reset the expiration counter
+            let mut exp = 0u64;
+            unsafe { libc::read(timer.0, &mut exp as *mut _ as *mut _, mem::size_of::<u64>()) };
+        }
+        let dir = match (events, data.handle) {
+            (Some(events), Some(_)) => Some(epoll_to_event(events)),
+            _ => None,
+        };
+        if data.kind.call_mut(cbid, dir) {
+            self.cb_map.borrow_mut().insert(cbid, data);
+        } else {
+            self.remove(&data);
+            data.kind.post_call_mut(cbid);
+        }
+        true
+    }
+
+    fn remove(&self, data: &Data<'a>) {
+        if let Some((fd, _)) = data.handle {
+            unsafe { libc::epoll_ctl(self.epoll.0, libc::EPOLL_CTL_DEL, fd, ptr::null_mut()) };
+        }
+    }
+
+    fn call_thread_msgs(&self) {
+        let mut count = 0u64;
+        unsafe { libc::read(self.wake.0, &mut count as *mut _ as *mut _, mem::size_of::<u64>()) };
+        while let Ok(cb) = self.recv.try_recv() {
+            catch_panic(None, || cb.call(), ());
+        }
+    }
+
+    pub fn run_one(&self, wait: bool) -> bool {
+        let timeout = if wait && self.asap.borrow().is_empty() && self.idle.borrow().is_empty() { -1 } else { 0 };
+        let mut events: [libc::epoll_event; 32] = unsafe { mem::zeroed() };
+        let n = unsafe { libc::epoll_wait(self.epoll.0, events.as_mut_ptr(), events.len() as libc::c_int, timeout) };
+
+        let mut ready: Vec<_> = {
+            let cb_map = self.cb_map.borrow();
+            events.iter().take(std::cmp::max(n, 0) as usize).map(|ev| {
+                let priority = cb_map.get(&CbId(ev.u64)).map(|d| d.priority).unwrap_or_default();
+                (priority, ev.u64, ev.events)
+            }).collect()
+        };
+        ready.sort_by_key(|x| x.0);
+
+        let mut called = false;
+        for (_, token, flags) in ready {
+            if token == WAKE_TOKEN {
+                self.call_thread_msgs();
+                called = true;
+            } else if self.call_data(CbId(token), Some(flags)) {
+                called = true;
+            }
+        }
+
+        let asap = self.asap.borrow_mut().pop_front();
+        if let Some((_, cbid)) = asap {
+            called |= self.call_data(cbid, None);
+        }
+        if !called { called = self.run_idle(); }
+        called
+    }
+
+    fn run_idle(&self) -> bool {
+        let cbid = self.idle.borrow_mut().pop_front();
+        if let Some(cbid) = cbid {
+            self.call_data(cbid, None);
+            if self.cb_map.borrow().contains_key(&cbid) { self.idle.borrow_mut().push_back(cbid); }
+            true
+        } else { false }
+    }
+
+    pub (crate) fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
+        let data = self.cb_map.borrow_mut().remove(&cbid)?;
+        self.remove(&data);
+        let mut asap = self.asap.borrow_mut();
+        if let Some(idx) = asap.iter().position(|x| x.1 == cbid) { asap.remove(idx); }
+        let mut idle = self.idle.borrow_mut();
+        if let Some(idx) = idle.iter().position(|x| *x == cbid) { idle.remove(idx); }
+        Some(data.kind)
+    }
+
+    pub (crate) fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> bool {
+        let mut cb_map = self.cb_map.borrow_mut();
+        let data = match cb_map.get_mut(&cbid) { Some(x) => x, None => return false };
+        if let Some((fd, d)) = &mut data.handle {
+            let mut ev = libc::epoll_event { events: dir_to_epoll(dir), u64: cbid.0 };
+            if unsafe { libc::epoll_ctl(self.epoll.0, libc::EPOLL_CTL_MOD, *fd, &mut ev) } < 0 { return false };
+            *d = dir;
+            true
+        } else { false }
+    }
+
+    pub (crate) fn push(&self, cbid: CbId, cb: CbKind<'a>, priority: Priority) -> Result<(), MainLoopError> {
+        let mut data = Data { kind: cb, priority, handle: None, timer: None };
+        if let Some((handle, direction)) = data.kind.handle() {
+            let mut ev = libc::epoll_event { events: dir_to_epoll(direction), u64: cbid.0 };
+            if unsafe { libc::epoll_ctl(self.epoll.0, libc::EPOLL_CTL_ADD, handle.0, &mut ev) } < 0 {
+                return Err(MainLoopError::IoRegistration(io::Error::last_os_error()));
+            }
+            data.handle = Some((handle.0, direction));
+        } else if let Some(d) = data.kind.duration() {
+            let timer = Fd(cvt(unsafe { libc::timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC | libc::TFD_NONBLOCK) })?);
+            let value = duration_to_timespec(d);
+            let interval = if let CbKind::Interval(_, _) = data.kind { value } else { unsafe { mem::zeroed() } };
+            let spec = libc::itimerspec { it_interval: interval, it_value: value };
+            cvt(unsafe { libc::timerfd_settime(timer.0, 0, &spec, ptr::null_mut()) })?;
+            let mut ev = libc::epoll_event { events: libc::EPOLLIN as u32, u64: cbid.0 };
+            cvt(unsafe { libc::epoll_ctl(self.epoll.0, libc::EPOLL_CTL_ADD, timer.0, &mut ev) })?;
+            data.timer = Some(timer);
+        } else if data.kind.is_idle() {
+            self.idle.borrow_mut().push_back(cbid);
+        } else {
+            let mut asap = self.asap.borrow_mut();
+            let idx = asap.iter().position(|x| x.0 > priority).unwrap_or(asap.len());
+            asap.insert(idx, (priority, cbid));
+        }
+        self.cb_map.borrow_mut().insert(cbid, data);
+        Ok(())
+    }
+}
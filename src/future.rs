@@ -1,47 +1,207 @@
-//! 0.3 Futures support (requires the "futures" feature).
+//! Futures support (requires the "futures" feature).
 
-use futures::future::{Future};
-use futures::task;
+use std::future::Future;
+use std::task::{Poll, Waker, Context};
+use futures::task::{self, ArcWake, Spawn, LocalSpawn, SpawnError, FutureObj, LocalFutureObj};
 use futures::stream::Stream;
-use futures::task::{Poll, Waker, Context, ArcWake};
+use futures::future;
 use std::pin::Pin;
 use std::mem;
 use std::sync::{Arc, Mutex};
-use crate::{MainLoopError, MainLoop, IODirection, CbHandle, IOAble};
+use crate::{CbId, MainLoopError, MainLoop, MainLoopHandle, IODirection, IOEvent, CbHandle, IOAble};
 use std::collections::{HashMap, VecDeque};
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::cell::{Cell, RefCell};
+use std::thread::ThreadId;
+use futures::channel::oneshot;
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+#[cfg(unix)]
+mod async_io;
+#[cfg(unix)]
+pub use async_io::Async;
+
+#[cfg(unix)]
+pub mod net;
+
+type SharedWaker = Rc<RefCell<Option<Waker>>>;
+
+fn set_waker(w: &SharedWaker, ctx: &Context) {
+    let mut w = w.borrow_mut();
+    if !w.as_ref().map(|w| w.will_wake(ctx.waker())).unwrap_or(false) { *w = Some(ctx.waker().clone()) }
+}
+
+fn wake(w: &SharedWaker) {
+    if let Some(w) = &*w.borrow() { w.wake_by_ref() }
+}
 
 /// Waits until a specific instant.
-pub struct Delay(Instant);
+///
+/// At most one timer is registered with the main loop, and it is cancelled if the Delay is dropped.
+pub struct Delay {
+    at: Instant,
+    timer: Option<CbId>,
+    // Cleared when the timer fires, so it is registered again if it fired early
+    armed: Rc<Cell<bool>>,
+    waker: SharedWaker,
+}
 
 impl Future for Delay {
     type Output = Result<(), MainLoopError>;
-    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
-        let n = Instant::now();
-        // println!("Polled at {:?}", n);
-        if self.0 <= n { Poll::Ready(Ok(())) }
-        else {
-            let lw = ctx.waker().clone();
-            match crate::call_after(self.0 - n, move || { lw.wake() }) {
-                Ok(_) => Poll::Pending,
-                Err(e) => Poll::Ready(Err(e)),
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        if self.at <= Instant::now() {
+            if self.armed.replace(false) {
+                if let Some(id) = self.timer.take() { let _ = crate::cancel(id); }
+            }
+            return Poll::Ready(Ok(()));
+        }
+        set_waker(&self.waker, ctx);
+        if !self.armed.get() {
+            let (w, armed) = (self.waker.clone(), self.armed.clone());
+            match crate::call_at(self.at, move || { armed.set(false); wake(&w) }) {
+                Ok(id) => { self.timer = Some(id); self.armed.set(true); },
+                Err(e) => return Poll::Ready(Err(e)),
             }
         }
+        Poll::Pending
+    }
+}
+
+impl Drop for Delay {
+    fn drop(&mut self) {
+        if self.armed.get() {
+            if let Some(id) = self.timer { let _ = crate::cancel(id); }
+        }
     }
 }
 
 /// Waits until a specific instant.
 pub fn delay(i: Instant) -> Delay {
-    Delay(i)
+    Delay { at: i, timer: None, armed: Default::default(), waker: Default::default() }
+}
+
+/// Waits for a duration.
+pub fn sleep(d: Duration) -> Delay {
+    delay(Instant::now() + d)
+}
+
+/// What an Interval does when ticks are missed, because the stream was not polled in time.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum MissedTickBehavior {
+    /// Outputs all missed ticks right away, then continues on the original schedule.
+    #[default]
+    Burst,
+    /// Outputs one tick right away, then continues one period after that.
+    Delay,
+    /// Outputs one tick right away, then continues on the original schedule.
+    Skip,
+}
+
+/// A stream that outputs the instant a tick was due, at regular intervals.
+///
+/// The first tick is due one period after the Interval was created.
+/// A single interval timer is registered with the main loop, and it is cancelled if the Interval is dropped.
+pub struct Interval {
+    period: Duration,
+    next: Instant,
+    behavior: MissedTickBehavior,
+    timer: CbId,
+    // Number of times the timer has fired, but the tick has not been output yet
+    fired: Rc<Cell<u32>>,
+    waker: SharedWaker,
+}
+
+fn register_interval(period: Duration, fired: &Rc<Cell<u32>>, waker: &SharedWaker) -> Result<CbId, MainLoopError> {
+    let (fired, waker) = (fired.clone(), waker.clone());
+    crate::call_interval(period, move || {
+        fired.set(fired.get().saturating_add(1));
+        wake(&waker);
+        true
+    })
+}
+
+impl Interval {
+    /// Sets what to do when ticks are missed. The default is Burst.
+    pub fn set_missed_tick_behavior(&mut self, b: MissedTickBehavior) { self.behavior = b; }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+    fn poll_next(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
+        let n = self.fired.get();
+        if n == 0 {
+            set_waker(&self.waker, ctx);
+            return Poll::Pending;
+        }
+        let (tick, period) = (self.next, self.period);
+        match self.behavior {
+            MissedTickBehavior::Burst => {
+                self.fired.set(n - 1);
+                self.next += period;
+            }
+            MissedTickBehavior::Skip => {
+                self.fired.set(0);
+                self.next += period * n;
+            }
+            MissedTickBehavior::Delay => {
+                self.fired.set(0);
+                self.next += period;
+                if n > 1 {
+                    // Restart the timer from now. If that fails, stay on the old schedule.
+                    if let Ok(id) = register_interval(period, &self.fired, &self.waker) {
+                        let _ = crate::cancel(self.timer);
+                        self.timer = id;
+                        self.next = Instant::now() + period;
+                    }
+                }
+            }
+        }
+        Poll::Ready(Some(tick))
+    }
+}
+
+impl Drop for Interval {
+    fn drop(&mut self) { let _ = crate::cancel(self.timer); }
+}
+
+/// Creates an Interval with the given period.
+///
+/// Fails if there is no main loop running on this thread.
+pub fn interval(period: Duration) -> Result<Interval, MainLoopError> {
+    let fired = Rc::new(Cell::new(0));
+    let waker = SharedWaker::default();
+    let timer = register_interval(period, &fired, &waker)?;
+    Ok(Interval { period, next: Instant::now() + period, behavior: Default::default(), timer, fired, waker })
+}
+
+/// Resolves to the output of a future, or to MainLoopError::TimedOut if it takes too long.
+pub struct Timeout<F> {
+    f: Pin<Box<F>>,
+    delay: Delay,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, MainLoopError>;
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(r) = self.f.as_mut().poll(ctx) { return Poll::Ready(Ok(r)) };
+        match Pin::new(&mut self.delay).poll(ctx) {
+            Poll::Ready(Ok(())) => Poll::Ready(Err(MainLoopError::TimedOut)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Runs a future, but gives up if it has not completed within the duration.
+pub fn timeout<F: Future>(d: Duration, f: F) -> Timeout<F> {
+    Timeout { f: Box::pin(f), delay: sleep(d) }
 }
 
 struct IoInternal {
     cb_handle: CbHandle,
     direction: IODirection,
-    queue: RefCell<VecDeque<Result<IODirection, std::io::Error>>>,
+    queue: RefCell<VecDeque<Result<IOEvent, std::io::Error>>>,
     alive: Cell<bool>,
     started: Cell<bool>,
     waker: RefCell<Option<Waker>>,
@@ -54,7 +214,7 @@ pub struct Io(Rc<IoInternal>);
 impl IOAble for Io {
     fn handle(&self) -> CbHandle { self.0.cb_handle }
     fn direction(&self) -> IODirection { self.0.direction }
-    fn on_rw(&mut self, r: Result<IODirection, std::io::Error>) -> bool {
+    fn on_rw(&mut self, r: Result<IOEvent, std::io::Error>) -> bool {
         self.0.queue.borrow_mut().push_back(r);
         let w = self.0.waker.borrow();
         if let Some(waker) = &*w { waker.wake_by_ref() };
@@ -63,14 +223,14 @@ impl IOAble for Io {
 }
 
 impl Stream for Io {
-    type Item = Result<IODirection, MainLoopError>;
+    type Item = Result<IOEvent, MainLoopError>;
     fn poll_next(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Option<Self::Item>> {
-        let s: &IoInternal = &(*self).0;
+        let s: &IoInternal = &self.0;
         if !s.alive.get() { return Poll::Ready(None); }
 
         if !s.started.get() {
             // Submit to the reactor
-            let c: &Rc<IoInternal> = &(*self).0;
+            let c: &Rc<IoInternal> = &self.0;
             let c = Io(c.clone());
             if let Err(e) = crate::call_io(c) {
                 s.alive.set(false);
@@ -92,7 +252,7 @@ impl Stream for Io {
 
 impl Drop for Io {
     fn drop(&mut self) {
-        let s: &IoInternal = &(*self).0;
+        let s: &IoInternal = &self.0;
         s.alive.set(false);
     }
 }
@@ -109,18 +269,81 @@ pub fn io(handle: CbHandle, dir: IODirection) -> Io {
     }))
 }
 
+/// Resolves to the result of a function run on another thread.
+pub struct ThreadResult<R>(oneshot::Receiver<R>);
+
+impl<R> Future for ThreadResult<R> {
+    type Output = Result<R, MainLoopError>;
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(ctx).map(|r| r.map_err(|_| MainLoopError::MainLoopDropped))
+    }
+}
+
+/// Runs a function on another thread, and returns a future for its result.
+///
+/// The target thread must run a main loop.
+pub fn call_thread_with_result<R, F>(thread: ThreadId, f: F) -> Result<ThreadResult<R>, MainLoopError>
+where R: Send + 'static, F: FnOnce() -> R + Send + 'static {
+    let (tx, rx) = oneshot::channel();
+    crate::call_thread(thread, move || { let _ = tx.send(f()); })?;
+    Ok(ThreadResult(rx))
+}
+
+/// Resolves to the output of a child process.
+#[cfg(unix)]
+pub struct ChildOutput(oneshot::Receiver<std::io::Result<std::process::Output>>);
+
+#[cfg(unix)]
+impl Future for ChildOutput {
+    type Output = Result<std::process::Output, MainLoopError>;
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(ctx).map(|r| match r {
+            Ok(Ok(o)) => Ok(o),
+            Ok(Err(e)) => Err(MainLoopError::Other(e.into())),
+            Err(_) => Err(MainLoopError::MainLoopDropped),
+        })
+    }
+}
+
+/// Spawns a child process, and returns a future for its exit status and everything it wrote to stdout and stderr.
+///
+/// See the process module for details.
+#[cfg(unix)]
+pub fn child_output(cmd: &mut std::process::Command) -> Result<ChildOutput, MainLoopError> {
+    use crate::process::Pipe;
+    let (tx, rx) = oneshot::channel();
+    let out = Rc::new(RefCell::new((vec!(), vec!())));
+    let out2 = out.clone();
+    crate::process::call_child(cmd, move |pipe, data| {
+        let mut o = out.borrow_mut();
+        if pipe == Pipe::Stdout { o.0.extend_from_slice(data) } else { o.1.extend_from_slice(data) }
+    }, move |status| {
+        let (stdout, stderr) = out2.replace((vec!(), vec!()));
+        let _ = tx.send(status.map(|status| std::process::Output { status, stdout, stderr }));
+    })?;
+    Ok(ChildOutput(rx))
+}
+
 // And the executor stuff 
 
-type BoxFuture<'a> = Pin<Box<Future<Output=()> + 'a>>;
+type BoxFuture<'a> = Pin<Box<dyn Future<Output=()> + 'a>>;
+
+type SendBoxFuture = Pin<Box<dyn Future<Output=()> + Send + 'static>>;
 
 type RunQueue = Arc<Mutex<Vec<u64>>>;
 
-struct Task(u64, RunQueue);
+type SpawnQueue = Arc<Mutex<Vec<SendBoxFuture>>>;
+
+type LocalSpawnQueue = Rc<RefCell<Vec<BoxFuture<'static>>>>;
+
+struct Task(u64, RunQueue, ThreadId, MainLoopHandle);
 
 impl ArcWake for Task {
     fn wake_by_ref(x: &Arc<Self>) {
         x.1.lock().unwrap().push(x.0);
         // println!("Waking up");
+        // If we're woken up from another thread, the main loop might be waiting.
+        if std::thread::current().id() != x.2 { let _ = x.3.call_asap(|| {}); }
     }
 }
 
@@ -131,14 +354,25 @@ impl ArcWake for Task {
 /// It contains a MainLoop inside, so you can spawn 'static callbacks too. 
 pub struct Executor<'a> {
     ml: MainLoop<'a>,
+    handle: MainLoopHandle,
     tasks: HashMap<u64, BoxFuture<'a>>,
     next_task: u64,
     run_queue: RunQueue,
+    spawn_queue: SpawnQueue,
+    local_spawn_queue: LocalSpawnQueue,
 }
 
 impl<'a> Executor<'a> {
     pub fn new() -> Result<Self, MainLoopError> {
-        Ok(Executor { ml: MainLoop::new()?, next_task: 1, run_queue: Default::default(), tasks: Default::default() })
+        let ml = MainLoop::new()?;
+        let handle = ml.handle();
+        Ok(Executor { ml, handle, next_task: 1, run_queue: Default::default(),
+            spawn_queue: Default::default(), local_spawn_queue: Default::default(), tasks: Default::default() })
+    }
+
+    /// Returns a Spawner, which can spawn futures on this executor from other threads.
+    pub fn spawner(&self) -> Spawner {
+        Spawner { handle: self.handle.clone(), queue: self.spawn_queue.clone() }
     }
 
     /// Runs until the main loop is terminated.
@@ -146,16 +380,33 @@ impl<'a> Executor<'a> {
         while self.run_one(true) {}
     }
 
+    /// Runs until the main loop is terminated, and returns the value passed to `terminate_with`.
+    ///
+    /// Returns None if the main loop was terminated some other way, or with a value of another type.
+    pub fn run_with<T: std::any::Any>(&mut self) -> Option<T> {
+        self.run();
+        crate::mainloop::take_exit_value()
+    }
+
+    /// Returns a LocalSpawner, which can spawn futures on this executor from its own thread.
+    pub fn local_spawner(&self) -> LocalSpawner {
+        LocalSpawner(Rc::downgrade(&self.local_spawn_queue))
+    }
+
     /// Processes futures ready to make progress.
     ///
     /// If no futures are ready to progress, may block in case allow_wait is true.
     /// Returns false if the mainloop was terminated.
     pub fn run_one(&mut self, allow_wait: bool) -> bool {
+        let spawned = mem::take(&mut *self.spawn_queue.lock().unwrap());
+        for f in spawned { self.spawn_boxed(f) }
+        let spawned = mem::take(&mut *self.local_spawn_queue.borrow_mut());
+        for f in spawned { self.spawn_boxed(f) }
         let run_queue: Vec<_> = {
             let mut r = self.run_queue.lock().unwrap();
-            mem::replace(&mut *r, vec!())
+            mem::take(&mut *r)
         };
-        if run_queue.len() == 0 {
+        if run_queue.is_empty() {
             return self.ml.run_one(allow_wait);
         }
         for id in run_queue {
@@ -163,11 +414,11 @@ impl<'a> Executor<'a> {
                 let f = self.tasks.get_mut(&id);
                 if let Some(f) = f {
                     let pinf = f.as_mut();
-                    let t = Task(id, self.run_queue.clone());
+                    let t = Task(id, self.run_queue.clone(), std::thread::current().id(), self.handle.clone());
                     let t = Arc::new(t);
                     let waker = task::waker_ref(&t);
                     let mut ctx = Context::from_waker(&waker);
-                    pinf.poll(&mut ctx) != futures::Poll::Pending
+                    pinf.poll(&mut ctx).is_ready()
                 } else { false }
             };
             if remove {
@@ -177,6 +428,12 @@ impl<'a> Executor<'a> {
         true
     }
 
+    fn spawn_boxed(&mut self, f: BoxFuture<'a>) {
+        self.tasks.insert(self.next_task, f);
+        self.run_queue.lock().unwrap().push(self.next_task);
+        self.next_task += 1;
+    }
+
     /// Runs until the future is ready, or the main loop is terminated.
     ///
     /// Returns None if the main loop is terminated, or the result of the future otherwise.
@@ -185,7 +442,7 @@ impl<'a> Executor<'a> {
         let res = Arc::new(RefCell::new(None));
         let res2 = res.clone();
         let f = f.then(move |r| { *res2.borrow_mut() = Some(r); ready(()) });
-        self.spawn(f);
+        self.spawn_boxed(Box::pin(f));
         loop {
             if !self.run_one(true) { return None };
             let x = res.borrow_mut().take();
@@ -193,11 +450,110 @@ impl<'a> Executor<'a> {
         }
     }
 
-    pub fn spawn<F: Future<Output=()> + 'a>(&mut self, f: F) {
-        let x = Box::pin(f);
-        self.tasks.insert(self.next_task, x);
-        self.run_queue.lock().unwrap().push(self.next_task);
-        self.next_task += 1;
+    /// Spawns a future on the executor.
+    ///
+    /// The returned JoinHandle resolves to the future's output. Dropping it cancels
+    /// the task, unless `detach` is called first.
+    pub fn spawn<T: 'a, F: Future<Output=T> + 'a>(&mut self, f: F) -> JoinHandle<T> {
+        let (f, abort) = future::abortable(f);
+        let (tx, rx) = oneshot::channel();
+        self.spawn_boxed(Box::pin(async move {
+            if let Ok(r) = f.await { let _ = tx.send(r); }
+        }));
+        JoinHandle { rx, abort: Some(abort) }
+    }
+}
+
+/// Spawns futures on an Executor from any thread.
+///
+/// The futures run on the executor's thread.
+#[derive(Clone)]
+pub struct Spawner {
+    handle: MainLoopHandle,
+    queue: SpawnQueue,
+}
+
+impl Spawner {
+    /// Spawns a future on the executor.
+    ///
+    /// Fails if the Executor has been dropped. The JoinHandle works the same way as the one
+    /// returned from `Executor::spawn`.
+    pub fn spawn<T, F>(&self, f: F) -> Result<JoinHandle<T>, MainLoopError>
+    where T: Send + 'static, F: Future<Output=T> + Send + 'static {
+        let (f, abort) = future::abortable(f);
+        let (tx, rx) = oneshot::channel();
+        self.push(Box::pin(async move {
+            if let Ok(r) = f.await { let _ = tx.send(r); }
+        }))?;
+        Ok(JoinHandle { rx, abort: Some(abort) })
+    }
+
+    // Queues the future from the executor's thread, which also wakes it up.
+    // If the Executor is gone, the future is dropped along with the call.
+    fn push(&self, f: SendBoxFuture) -> Result<(), MainLoopError> {
+        let q = self.queue.clone();
+        self.handle.call_asap(move || q.lock().unwrap().push(f)).map(|_| ())
+    }
+}
+
+impl Spawn for Spawner {
+    fn spawn_obj(&self, f: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        self.push(Box::pin(f)).map_err(|_| SpawnError::shutdown())
+    }
+}
+
+/// Spawns futures on an Executor from the executor's thread.
+///
+/// It implements the `LocalSpawn` and `Spawn` traits from the futures crate, so
+/// it can be handed to libraries that need to spawn tasks of their own.
+#[derive(Clone)]
+pub struct LocalSpawner(Weak<RefCell<Vec<BoxFuture<'static>>>>);
+
+impl LocalSpawn for LocalSpawner {
+    fn spawn_local_obj(&self, f: LocalFutureObj<'static, ()>) -> Result<(), SpawnError> {
+        let q = self.0.upgrade().ok_or_else(SpawnError::shutdown)?;
+        q.borrow_mut().push(Box::pin(f));
+        Ok(())
+    }
+}
+
+impl Spawn for LocalSpawner {
+    fn spawn_obj(&self, f: FutureObj<'static, ()>) -> Result<(), SpawnError> {
+        self.spawn_local_obj(f.into())
+    }
+}
+
+/// A handle to a task spawned on an Executor.
+///
+/// It is a future that resolves to the task's output, or to an error if the
+/// Executor is dropped before the task finishes.
+/// Dropping the handle cancels the task, unless `detach` is called first.
+#[must_use = "dropping a JoinHandle cancels the task, use detach() to let it run"]
+pub struct JoinHandle<T> {
+    rx: oneshot::Receiver<T>,
+    abort: Option<future::AbortHandle>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Lets the task run to completion without anyone waiting for its output.
+    pub fn detach(mut self) { self.abort.take(); }
+
+    /// Cancels the task. The future is dropped the next time the Executor runs.
+    pub fn abort(mut self) {
+        if let Some(a) = self.abort.take() { a.abort() }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, MainLoopError>;
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(ctx).map(|r| r.map_err(|_| MainLoopError::MainLoopDropped))
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if let Some(a) = self.abort.take() { a.abort() }
     }
 }
 
@@ -209,7 +565,7 @@ fn delay_test() {
     let mut x = Executor::new().unwrap();
     let n = Instant::now() + Duration::from_millis(200);
     let f = delay(n).then(|_| { println!("Terminating!"); crate::terminate(); ready(()) });
-    x.spawn(f);
+    x.spawn(f).detach();
     x.run();
     assert!(Instant::now() >= n);
 }
@@ -227,3 +583,168 @@ fn async_fn_test() {
     x.block_on(foo(n));
     assert!(Instant::now() >= n);
 }
+
+#[test]
+fn thread_result_test() {
+    use std::thread;
+    use std::sync::mpsc::channel;
+
+    let (tx, rx) = channel();
+    let t = thread::spawn(move || {
+        let mut ml = MainLoop::new().unwrap();
+        tx.send(thread::current().id()).unwrap();
+        ml.run();
+    });
+    let id = rx.recv().unwrap();
+    let mut x = Executor::new().unwrap();
+    let r = x.block_on(call_thread_with_result(id, || 5 + 2).unwrap()).unwrap();
+    assert_eq!(r.unwrap(), 7);
+    crate::call_thread(id, crate::terminate).unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn join_handle_test() {
+    use std::time::Duration;
+
+    let ran = Cell::new(false);
+    let mut x = Executor::new().unwrap();
+    let a = x.spawn(async { 5 });
+    let b = x.spawn(async { 7 });
+    assert_eq!(x.block_on(async { a.await.unwrap() + b.await.unwrap() }), Some(12));
+
+    let c = x.spawn(async {
+        delay(Instant::now() + Duration::from_millis(100)).await.unwrap();
+        ran.set(true);
+    });
+    x.block_on(async { delay(Instant::now() + Duration::from_millis(50)).await.unwrap() });
+    c.abort();
+    x.block_on(async { delay(Instant::now() + Duration::from_millis(100)).await.unwrap() });
+    assert!(!ran.get());
+    assert_eq!(x.tasks.len(), 0);
+}
+
+#[test]
+fn spawner_test() {
+    use std::thread;
+
+    let mut x = Executor::new().unwrap();
+    let spawner = x.spawner();
+    let t = thread::spawn(move || {
+        let main_thread = spawner.spawn(async { thread::current().id() }).unwrap();
+        spawner.spawn(async { crate::terminate() }).unwrap().detach();
+        main_thread
+    });
+    x.run();
+    let h = t.join().unwrap();
+    assert_eq!(x.block_on(h).unwrap().unwrap(), thread::current().id());
+}
+
+#[test]
+fn spawner_dropped_test() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct SetOnDrop(Arc<AtomicBool>);
+    impl Drop for SetOnDrop {
+        fn drop(&mut self) { self.0.store(true, Ordering::SeqCst); }
+    }
+
+    let spawner = Executor::new().unwrap().spawner();
+    let dropped = Arc::new(AtomicBool::new(false));
+    let d = SetOnDrop(dropped.clone());
+    let r = spawner.spawn(async move { drop(d) });
+    assert!(matches!(r, Err(MainLoopError::MainLoopDropped)));
+    assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn local_spawner_test() {
+    use futures::task::LocalSpawnExt;
+
+    let mut x = Executor::new().unwrap();
+    let spawner = x.local_spawner();
+    let s2 = spawner.clone();
+    let h = spawner.spawn_local_with_handle(async move {
+        s2.spawn_local_with_handle(async { 3 }).unwrap().await + 4
+    }).unwrap();
+    assert_eq!(x.block_on(h), Some(7));
+    drop(x);
+    assert!(spawner.spawn_local(async {}).is_err());
+}
+
+#[test]
+fn timer_test() {
+    use futures::stream::StreamExt;
+
+    let mut x = Executor::new().unwrap();
+    let start = Instant::now();
+    x.block_on(async move {
+        let mut i = interval(Duration::from_millis(50)).unwrap();
+        for n in 1..=3 {
+            i.next().await.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(50 * n));
+        }
+    }).unwrap();
+
+    let r = x.block_on(timeout(Duration::from_millis(50), sleep(Duration::from_secs(5)))).unwrap();
+    assert!(matches!(r, Err(MainLoopError::TimedOut)));
+    assert!(Instant::now() < start + Duration::from_secs(5));
+    let r = x.block_on(timeout(Duration::from_secs(5), async { sleep(Duration::from_millis(10)).await.unwrap(); 5 })).unwrap();
+    assert_eq!(r.unwrap(), 5);
+}
+
+#[test]
+fn missed_tick_test() {
+    use futures::stream::StreamExt;
+
+    let p = Duration::from_millis(100);
+    let mut x = Executor::new().unwrap();
+    for b in [MissedTickBehavior::Burst, MissedTickBehavior::Skip, MissedTickBehavior::Delay] {
+        let start = Instant::now();
+        let (ticks, times) = x.block_on(async move {
+            let mut i = interval(p).unwrap();
+            i.set_missed_tick_behavior(b);
+            // Misses the ticks at 1 and 2 periods
+            sleep(p * 5 / 2).await.unwrap();
+            let (mut ticks, mut times) = (vec!(), vec!());
+            for _ in 0..3 {
+                ticks.push(i.next().await.unwrap());
+                times.push(start.elapsed());
+            }
+            (ticks, times)
+        }).unwrap();
+        let t0 = ticks[0] - p;
+        assert!(times[0] < p * 3);
+        match b {
+            MissedTickBehavior::Burst => {
+                assert_eq!(ticks[1] - t0, p * 2);
+                assert_eq!(ticks[2] - t0, p * 3);
+                assert!(times[1] < p * 3);
+                assert!(times[2] >= p * 3);
+            }
+            MissedTickBehavior::Skip => {
+                assert_eq!(ticks[1] - t0, p * 3);
+                assert_eq!(ticks[2] - t0, p * 4);
+                assert!(times[1] >= p * 3);
+                assert!(times[2] >= p * 4);
+            }
+            MissedTickBehavior::Delay => {
+                assert!(ticks[1] - t0 >= p * 7 / 2);
+                assert_eq!(ticks[2] - ticks[1], p);
+                assert!(times[1] >= p * 7 / 2);
+                assert!(times[2] >= p * 9 / 2);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn child_output_test() {
+    let mut x = Executor::new().unwrap();
+    let o = x.block_on(async {
+        child_output(std::process::Command::new("echo").arg("Hello")).unwrap().await.unwrap()
+    }).unwrap();
+    assert!(o.status.success());
+    assert_eq!(&*o.stdout, b"Hello\n");
+}
@@ -0,0 +1,260 @@
+//! Async reading and writing of nonblocking unix file descriptors.
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use futures::io::{AsyncRead, AsyncWrite};
+use crate::{CbId, CbHandle, IOAble, IODirection, IOEvent, MainLoopError};
+use super::{SharedWaker, set_waker, wake};
+
+fn dir_bits(d: IODirection) -> (bool, bool) {
+    match d {
+        IODirection::None => (false, false),
+        IODirection::Read => (true, false),
+        IODirection::Write => (false, true),
+        IODirection::Both => (true, true),
+    }
+}
+
+fn bits_dir(read: bool, write: bool) -> IODirection {
+    IOEvent { readable: read, writable: write, ..Default::default() }.direction()
+}
+
+pub (super) fn ml_err(e: MainLoopError) -> io::Error {
+    match e {
+        MainLoopError::IoRegistration(e) => e,
+        MainLoopError::Other(b) => match b.downcast::<io::Error>() {
+            Ok(e) => *e,
+            Err(b) => io::Error::other(b),
+        },
+        e => io::Error::other(e),
+    }
+}
+
+struct Source {
+    cbid: Cell<Option<CbId>>,
+    fd: RawFd,
+    interest: Cell<IODirection>,
+    readable: Cell<bool>,
+    writable: Cell<bool>,
+    read_waker: SharedWaker,
+    write_waker: SharedWaker,
+}
+
+impl Source {
+    fn interest(&self) -> IODirection { self.interest.get() }
+
+    fn set_interest(&self, d: IODirection) {
+        if self.interest.replace(d) == d { return; }
+        if let Some(id) = self.cbid.get() { let _ = crate::set_io_direction(id, d); }
+    }
+}
+
+// The main loop's end of an Async
+struct Reg(Rc<Source>);
+
+impl IOAble for Reg {
+    fn handle(&self) -> CbHandle { CbHandle(self.0.fd) }
+    fn direction(&self) -> IODirection { self.0.interest() }
+    fn on_rw(&mut self, r: Result<IOEvent, io::Error>) -> bool {
+        let s = &self.0;
+        let (read, write) = match r {
+            Ok(ev) if !ev.error && !ev.hangup => (ev.readable || ev.priority, ev.writable),
+            _ => {
+                // Errors and hangups are reported regardless of interest, so stay off the main loop
+                // until someone gets WouldBlock again. Wake up everyone, and let them find out from
+                // the next read or write.
+                s.cbid.set(None);
+                s.interest.set(IODirection::None);
+                s.readable.set(true);
+                s.writable.set(true);
+                wake(&s.read_waker);
+                wake(&s.write_waker);
+                return false;
+            }
+        };
+        let (want_read, want_write) = dir_bits(s.interest());
+        // Poll is level triggered, so stop waiting until someone gets WouldBlock again.
+        s.set_interest(bits_dir(want_read && !read, want_write && !write));
+        if read { s.readable.set(true); wake(&s.read_waker); }
+        if write { s.writable.set(true); wake(&s.write_waker); }
+        true
+    }
+}
+
+/// Wraps a file descriptor, such as a socket or a pipe, for async reading and writing on the main loop.
+///
+/// The file descriptor is set to nonblocking mode. Whenever an operation would block, the task
+/// waits for the main loop to report that the file descriptor is ready.
+///
+/// Implements `AsyncRead` and `AsyncWrite` if the wrapped type implements `Read` and `Write`.
+pub struct Async<T: AsRawFd + 'static> {
+    io: Option<T>,
+    source: Rc<Source>,
+}
+
+impl<T: AsRawFd + 'static> Async<T> {
+    /// Sets the file descriptor to nonblocking mode and registers it with the current thread's main loop.
+    pub fn new(io: T) -> Result<Self, MainLoopError> {
+        let fd = io.as_raw_fd();
+        crate::unix::set_nonblocking(fd)?;
+        let source = Rc::new(Source {
+            cbid: Cell::new(None),
+            fd,
+            interest: Cell::new(IODirection::None),
+            readable: Cell::new(false),
+            writable: Cell::new(false),
+            read_waker: Default::default(),
+            write_waker: Default::default(),
+        });
+        let cbid = crate::call_io(Reg(source.clone()))?;
+        source.cbid.set(Some(cbid));
+        Ok(Async { io: Some(io), source })
+    }
+
+    /// Returns a reference to the wrapped object.
+    pub fn get_ref(&self) -> &T { self.io.as_ref().unwrap() }
+
+    /// Returns a mutable reference to the wrapped object.
+    pub fn get_mut(&mut self) -> &mut T { self.io.as_mut().unwrap() }
+
+    /// Removes the file descriptor from the main loop, and returns the wrapped object.
+    ///
+    /// The file descriptor is still in nonblocking mode.
+    pub fn into_inner(mut self) -> T {
+        if let Some(id) = self.source.cbid.take() { let _ = crate::cancel(id); }
+        self.io.take().unwrap()
+    }
+
+    fn poll_op<R>(source: &Rc<Source>, read: bool, ctx: &Context, mut op: impl FnMut() -> io::Result<R>) -> Poll<io::Result<R>> {
+        let (ready, waker) = if read { (&source.readable, &source.read_waker) } else { (&source.writable, &source.write_waker) };
+        loop {
+            match op() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                r => return Poll::Ready(r),
+            }
+            // Something might have become ready since the operation was tried.
+            if ready.replace(false) { continue; }
+            set_waker(waker, ctx);
+            let (r, w) = dir_bits(source.interest());
+            source.set_interest(bits_dir(r || read, w || !read));
+            // Removed from the main loop after a hangup or error
+            if source.cbid.get().is_none() {
+                match crate::call_io(Reg(source.clone())) {
+                    Ok(id) => source.cbid.set(Some(id)),
+                    Err(e) => return Poll::Ready(Err(ml_err(e))),
+                }
+            }
+            return Poll::Pending;
+        }
+    }
+
+    /// Polls an operation that reads from the wrapped object, until it does not return WouldBlock.
+    pub fn poll_read_with<R>(&self, ctx: &Context, mut op: impl FnMut(&T) -> io::Result<R>) -> Poll<io::Result<R>> {
+        let io = self.get_ref();
+        Self::poll_op(&self.source, true, ctx, || op(io))
+    }
+
+    /// Polls an operation that writes to the wrapped object, until it does not return WouldBlock.
+    pub fn poll_write_with<R>(&self, ctx: &Context, mut op: impl FnMut(&T) -> io::Result<R>) -> Poll<io::Result<R>> {
+        let io = self.get_ref();
+        Self::poll_op(&self.source, false, ctx, || op(io))
+    }
+
+    /// Runs an operation that reads from the wrapped object, waiting as long as it returns WouldBlock.
+    ///
+    /// This is useful for operations not covered by `AsyncRead`, such as `UdpSocket::recv_from`.
+    pub async fn read_with<R>(&self, mut op: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        futures::future::poll_fn(|ctx| self.poll_read_with(ctx, &mut op)).await
+    }
+
+    /// Runs an operation that writes to the wrapped object, waiting as long as it returns WouldBlock.
+    pub async fn write_with<R>(&self, mut op: impl FnMut(&T) -> io::Result<R>) -> io::Result<R> {
+        futures::future::poll_fn(|ctx| self.poll_write_with(ctx, &mut op)).await
+    }
+}
+
+impl<T: AsRawFd + 'static> AsRawFd for Async<T> {
+    fn as_raw_fd(&self) -> RawFd { self.source.fd }
+}
+
+impl<T: AsRawFd + 'static> Drop for Async<T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.source.cbid.take() { let _ = crate::cancel(id); }
+        if let Some(io) = self.io.take() {
+            // Close the fd after the main loop has removed it. Otherwise a new
+            // fd with the same number could be removed instead.
+            let _ = crate::call_asap(move || drop(io));
+        }
+    }
+}
+
+impl<T: AsRawFd + Read + Unpin + 'static> AsyncRead for Async<T> {
+    fn poll_read(self: Pin<&mut Self>, ctx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let s = self.get_mut();
+        let io = s.io.as_mut().unwrap();
+        Self::poll_op(&s.source, true, ctx, || io.read(buf))
+    }
+}
+
+impl<T: AsRawFd + Write + Unpin + 'static> AsyncWrite for Async<T> {
+    fn poll_write(self: Pin<&mut Self>, ctx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let s = self.get_mut();
+        let io = s.io.as_mut().unwrap();
+        Self::poll_op(&s.source, false, ctx, || io.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+        let s = self.get_mut();
+        let io = s.io.as_mut().unwrap();
+        Self::poll_op(&s.source, false, ctx, || io.flush())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+        self.poll_flush(ctx)
+    }
+}
+
+#[test]
+fn async_test() {
+    use std::os::unix::net::UnixStream;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    // Big enough to fill up the socket buffer
+    let data: Vec<u8> = (0..1_000_000u32).map(|x| x as u8).collect();
+    let mut x = super::Executor::new().unwrap();
+    let (a, b) = UnixStream::pair().unwrap();
+    let (mut a, mut b) = (Async::new(a).unwrap(), Async::new(b).unwrap());
+    let d = &data;
+    let r = x.block_on(async move {
+        let w = async {
+            a.write_all(d).await.unwrap();
+            a.close().await.unwrap();
+            drop(a);
+        };
+        let mut buf = vec!();
+        let r = b.read_to_end(&mut buf);
+        let (_, r) = futures::join!(w, r);
+        r.unwrap();
+        buf
+    }).unwrap();
+    assert_eq!(r, data);
+}
+
+#[test]
+fn hangup_test() {
+    use std::os::unix::net::UnixStream;
+
+    let mut ml = crate::MainLoop::new().unwrap();
+    let (a, b) = UnixStream::pair().unwrap();
+    let _a = Async::new(a).unwrap();
+    drop(b);
+    ml.call_after(std::time::Duration::from_millis(200), crate::terminate).unwrap();
+    let mut count = 0;
+    while ml.run_one(true) { count += 1; }
+    assert!(count < 10, "Spinning: {} iterations", count);
+}
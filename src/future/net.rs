@@ -0,0 +1,345 @@
+//! Async TCP, UDP and Unix domain sockets, registered with the current thread's main loop.
+//!
+//! Reading and writing is done through the `AsyncRead` and `AsyncWrite` traits, e g through
+//! `futures::io::AsyncReadExt::read` and `futures::io::AsyncWriteExt::write`.
+
+use std::io;
+use std::mem;
+use std::net::{self, SocketAddr, ToSocketAddrs, Shutdown};
+use std::os::unix::net as unix;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::io::{AsyncRead, AsyncWrite};
+use super::Async;
+use super::async_io::ml_err;
+use crate::unix::{cvt, set_nonblocking};
+
+fn wrap<T: AsRawFd + 'static>(io: T) -> io::Result<Async<T>> { Async::new(io).map_err(ml_err) }
+
+// Starts a nonblocking connect, and waits for it to finish.
+async fn connect<T: AsRawFd + FromRawFd + 'static>(domain: libc::c_int, addr: *const libc::sockaddr, len: libc::socklen_t) -> io::Result<Async<T>> {
+    let fd = cvt(unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) })?;
+    // Closes the fd on errors
+    let s = unsafe { T::from_raw_fd(fd) };
+    unsafe {
+        cvt(libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC))?;
+    }
+    set_nonblocking(fd).map_err(ml_err)?;
+    match cvt(unsafe { libc::connect(fd, addr, len) }) {
+        Err(ref e) if e.raw_os_error() == Some(libc::EINPROGRESS) => {},
+        Err(e) => return Err(e),
+        Ok(_) => return wrap(s),
+    }
+    // Registered after connect, because a socket that is not connecting yet reports a hangup.
+    let s = wrap(s)?;
+    s.write_with(|s| {
+        let mut err: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        cvt(unsafe { libc::getsockopt(s.as_raw_fd(), libc::SOL_SOCKET, libc::SO_ERROR, &mut err as *mut _ as *mut _, &mut len) })?;
+        if err != 0 { return Err(io::Error::from_raw_os_error(err)) };
+        // SO_ERROR is also zero while the connect is in progress
+        let mut sa: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&sa) as libc::socklen_t;
+        match cvt(unsafe { libc::getpeername(s.as_raw_fd(), &mut sa as *mut _ as *mut _, &mut len) }) {
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOTCONN) => Err(io::ErrorKind::WouldBlock.into()),
+            r => r.map(|_| ()),
+        }
+    }).await?;
+    Ok(s)
+}
+
+macro_rules! async_rw {
+    ($t: ty) => {
+        impl AsyncRead for $t {
+            fn poll_read(mut self: Pin<&mut Self>, ctx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.0).poll_read(ctx, buf)
+            }
+        }
+
+        impl AsyncWrite for $t {
+            fn poll_write(mut self: Pin<&mut Self>, ctx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+                Pin::new(&mut self.0).poll_write(ctx, buf)
+            }
+            fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+                Pin::new(&mut self.0).poll_flush(ctx)
+            }
+            // Shuts down writing, so the peer gets an end of file
+            fn poll_close(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<io::Result<()>> {
+                futures::ready!(Pin::new(&mut self.0).poll_close(ctx))?;
+                Poll::Ready(self.shutdown(Shutdown::Write))
+            }
+        }
+
+        impl AsRawFd for $t {
+            fn as_raw_fd(&self) -> RawFd { self.0.as_raw_fd() }
+        }
+    }
+}
+
+/// A TCP socket server, listening for connections.
+pub struct TcpListener(Async<net::TcpListener>);
+
+impl TcpListener {
+    /// Creates a TCP listener bound to the address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::from_std(net::TcpListener::bind(addr)?)
+    }
+
+    /// Registers a std TcpListener with the main loop. It is set to nonblocking mode.
+    pub fn from_std(l: net::TcpListener) -> io::Result<Self> { Ok(TcpListener(wrap(l)?)) }
+
+    /// Waits for a new incoming connection.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let (s, addr) = self.0.read_with(|l| l.accept()).await?;
+        Ok((TcpStream::from_std(s)?, addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> { self.0.get_ref().local_addr() }
+}
+
+impl AsRawFd for TcpListener {
+    fn as_raw_fd(&self) -> RawFd { self.0.as_raw_fd() }
+}
+
+/// A TCP stream between a local and a remote socket.
+pub struct TcpStream(Async<net::TcpStream>);
+
+impl TcpStream {
+    /// Opens a TCP connection to a remote host.
+    ///
+    /// If the address resolves to several socket addresses, each of them is tried until
+    /// a connection succeeds. Note that resolving a host name blocks.
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match Self::connect_addr(addr).await {
+                Ok(s) => return Ok(s),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")))
+    }
+
+    async fn connect_addr(addr: SocketAddr) -> io::Result<Self> {
+        let s = match addr {
+            SocketAddr::V4(a) => {
+                let mut sa: libc::sockaddr_in = unsafe { mem::zeroed() };
+                sa.sin_family = libc::AF_INET as libc::sa_family_t;
+                sa.sin_port = a.port().to_be();
+                sa.sin_addr.s_addr = u32::from_ne_bytes(a.ip().octets());
+                connect(libc::AF_INET, &sa as *const _ as *const _, mem::size_of_val(&sa) as libc::socklen_t).await?
+            }
+            SocketAddr::V6(a) => {
+                let mut sa: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sa.sin6_port = a.port().to_be();
+                sa.sin6_flowinfo = a.flowinfo();
+                sa.sin6_addr.s6_addr = a.ip().octets();
+                sa.sin6_scope_id = a.scope_id();
+                connect(libc::AF_INET6, &sa as *const _ as *const _, mem::size_of_val(&sa) as libc::socklen_t).await?
+            }
+        };
+        Ok(TcpStream(s))
+    }
+
+    /// Registers a std TcpStream with the main loop. It is set to nonblocking mode.
+    pub fn from_std(s: net::TcpStream) -> io::Result<Self> { Ok(TcpStream(wrap(s)?)) }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> { self.0.get_ref().local_addr() }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> { self.0.get_ref().peer_addr() }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.0.get_ref().shutdown(how) }
+
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> { self.0.get_ref().set_nodelay(nodelay) }
+}
+
+async_rw!(TcpStream);
+
+/// A Unix domain socket server, listening for connections.
+pub struct UnixListener(Async<unix::UnixListener>);
+
+impl UnixListener {
+    /// Creates a Unix domain socket listener bound to the path.
+    pub fn bind<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_std(unix::UnixListener::bind(path)?)
+    }
+
+    /// Registers a std UnixListener with the main loop. It is set to nonblocking mode.
+    pub fn from_std(l: unix::UnixListener) -> io::Result<Self> { Ok(UnixListener(wrap(l)?)) }
+
+    /// Waits for a new incoming connection.
+    pub async fn accept(&self) -> io::Result<(UnixStream, unix::SocketAddr)> {
+        let (s, addr) = self.0.read_with(|l| l.accept()).await?;
+        Ok((UnixStream::from_std(s)?, addr))
+    }
+
+    pub fn local_addr(&self) -> io::Result<unix::SocketAddr> { self.0.get_ref().local_addr() }
+}
+
+impl AsRawFd for UnixListener {
+    fn as_raw_fd(&self) -> RawFd { self.0.as_raw_fd() }
+}
+
+/// A Unix domain stream socket.
+pub struct UnixStream(Async<unix::UnixStream>);
+
+impl UnixStream {
+    /// Connects to the socket at the path.
+    pub async fn connect<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().as_os_str().as_bytes();
+        let mut sa: libc::sockaddr_un = unsafe { mem::zeroed() };
+        sa.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        // Leave room for the terminating zero
+        if path.len() >= sa.sun_path.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "path too long for a Unix domain socket"));
+        }
+        for (d, s) in sa.sun_path.iter_mut().zip(path) { *d = *s as libc::c_char; }
+        let len = mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+        let s = connect(libc::AF_UNIX, &sa as *const _ as *const _, len as libc::socklen_t).await?;
+        Ok(UnixStream(s))
+    }
+
+    /// Creates a pair of connected sockets.
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (a, b) = unix::UnixStream::pair()?;
+        Ok((Self::from_std(a)?, Self::from_std(b)?))
+    }
+
+    /// Registers a std UnixStream with the main loop. It is set to nonblocking mode.
+    pub fn from_std(s: unix::UnixStream) -> io::Result<Self> { Ok(UnixStream(wrap(s)?)) }
+
+    pub fn local_addr(&self) -> io::Result<unix::SocketAddr> { self.0.get_ref().local_addr() }
+
+    pub fn peer_addr(&self) -> io::Result<unix::SocketAddr> { self.0.get_ref().peer_addr() }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> { self.0.get_ref().shutdown(how) }
+}
+
+async_rw!(UnixStream);
+
+/// A UDP socket.
+pub struct UdpSocket(Async<net::UdpSocket>);
+
+impl UdpSocket {
+    /// Creates a UDP socket bound to the address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::from_std(net::UdpSocket::bind(addr)?)
+    }
+
+    /// Registers a std UdpSocket with the main loop. It is set to nonblocking mode.
+    pub fn from_std(s: net::UdpSocket) -> io::Result<Self> { Ok(UdpSocket(wrap(s)?)) }
+
+    /// Sends data to the address. Returns the number of bytes sent.
+    pub async fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses to send data to"))?;
+        self.0.write_with(|s| s.send_to(buf, addr)).await
+    }
+
+    /// Waits for data. Returns the number of bytes read and the address it came from.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.0.read_with(|s| s.recv_from(buf)).await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> { self.0.get_ref().local_addr() }
+}
+
+impl AsRawFd for UdpSocket {
+    fn as_raw_fd(&self) -> RawFd { self.0.as_raw_fd() }
+}
+
+#[test]
+fn tcp_test() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut x = super::Executor::new().unwrap();
+    let r = x.block_on(async {
+        let l = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = l.local_addr().unwrap();
+        let server = async {
+            let (mut s, _) = l.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            s.read_exact(&mut buf).await.unwrap();
+            s.write_all(&buf).await.unwrap();
+            s.close().await.unwrap();
+            // Keep the socket open until the client has got its end of file
+            s
+        };
+        let client = async {
+            let mut s = TcpStream::connect(addr).await.unwrap();
+            s.write_all(b"Hello").await.unwrap();
+            let mut buf = vec!();
+            s.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        futures::join!(server, client).1
+    }).unwrap();
+    assert_eq!(&*r, b"Hello");
+}
+
+#[test]
+fn tcp_connect_fail_test() {
+    let mut x = super::Executor::new().unwrap();
+    // Nothing listens on a port that was just released
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let r = x.block_on(TcpStream::connect(addr)).unwrap();
+    assert_eq!(r.err().unwrap().kind(), io::ErrorKind::ConnectionRefused);
+
+    // Once the accept queue is full, the handshake does not finish until something is accepted
+    let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    assert_eq!(unsafe { libc::listen(l.as_raw_fd(), 0) }, 0);
+    let addr = l.local_addr().unwrap();
+    let _first = std::net::TcpStream::connect(addr).unwrap();
+    let r = x.block_on(super::timeout(std::time::Duration::from_millis(100), TcpStream::connect(addr))).unwrap();
+    assert!(matches!(r, Err(crate::MainLoopError::TimedOut)));
+}
+
+#[test]
+fn unix_test() {
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    let path = std::env::temp_dir().join(format!("thin_main_loop_test_{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut x = super::Executor::new().unwrap();
+    let r = x.block_on(async {
+        let l = UnixListener::bind(&path).unwrap();
+        let server = async {
+            let (mut s, _) = l.accept().await.unwrap();
+            s.write_all(b"Hello").await.unwrap();
+            s.close().await.unwrap();
+            s
+        };
+        let client = async {
+            let mut s = UnixStream::connect(&path).await.unwrap();
+            let mut buf = vec!();
+            s.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        futures::join!(server, client).1
+    }).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(&*r, b"Hello");
+}
+
+#[test]
+fn udp_test() {
+    let mut x = super::Executor::new().unwrap();
+    let (n, from, to) = x.block_on(async {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let recv = async {
+            let mut buf = [0u8; 16];
+            b.recv_from(&mut buf).await.unwrap()
+        };
+        let send = a.send_to(b"Hello", b.local_addr().unwrap());
+        let (r, s) = futures::join!(recv, send);
+        assert_eq!(s.unwrap(), 5);
+        (r.0, r.1, a.local_addr().unwrap())
+    }).unwrap();
+    assert_eq!(n, 5);
+    assert_eq!(from, to);
+}
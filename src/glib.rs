@@ -1,9 +1,8 @@
-use crate::{CbKind, CbId, MainLoopError, IODirection};
+use crate::{CbKind, CbId, MainLoopError, IODirection, IOEvent, Priority};
 use glib_sys;
 use std::{mem, panic};
 use std::ptr::NonNull;
 use crate::mainloop::{SendFnOnce, ffi_cb_wrapper};
-use std::os::raw::c_uint;
 use boxfnonce::SendBoxFnOnce;
 
 use std::cell::RefCell;
@@ -18,6 +17,23 @@ const G_SOURCE_FUNCS: glib_sys::GSourceFuncs = glib_sys::GSourceFuncs {
     closure_marshal: None, // GSourceDummyMarshal,
 };
 
+// Adds callbacks scheduled through the free functions, and removes finished callbacks,
+// also when something other than the MainLoop iterates the context.
+const G_SOURCE_FLUSH_FUNCS: glib_sys::GSourceFuncs = glib_sys::GSourceFuncs {
+    prepare: Some(glib_flush_prepare_cb),
+    check: Some(glib_flush_check_cb),
+    dispatch: Some(glib_flush_dispatch_cb),
+    finalize: None,
+    closure_callback: None,
+    closure_marshal: None,
+};
+
+#[repr(C)]
+struct GSourceFlushData {
+    gsource: glib_sys::GSource,
+    be: *const BeInternal<'static>,
+}
+
 #[repr(C)]
 struct GSourceIOData {
     gsource: glib_sys::GSource,
@@ -46,9 +62,42 @@ thread_local! {
     static FINISHED_TLS: RefCell<Vec<CbId>> = Default::default();
 }
 
-pub struct Backend<'a> {
+struct BeInternal<'a> {
     ctx: *mut glib_sys::GMainContext,
     cb_map: RefCell<HashMap<CbId, Box<CbData<'a>>>>,
+    flush: Option<GSourceRef>,
+}
+
+// Boxed because the flush source points to it
+pub struct Backend<'a>(Box<BeInternal<'a>>);
+
+fn flush_pending() -> bool {
+    crate::mainloop::has_queued() || crate::mainloop::has_unhandled_panics() || FINISHED_TLS.with(|f| !f.borrow().is_empty())
+}
+
+unsafe extern "C" fn glib_flush_prepare_cb(_: *mut glib_sys::GSource, timeout: *mut std::os::raw::c_int) -> glib_sys::gboolean {
+    *timeout = -1;
+    ffi_cb_wrapper(|| if flush_pending() { glib_sys::GTRUE } else { glib_sys::GFALSE }, glib_sys::GFALSE)
+}
+
+unsafe extern "C" fn glib_flush_check_cb(_: *mut glib_sys::GSource) -> glib_sys::gboolean {
+    ffi_cb_wrapper(|| if flush_pending() { glib_sys::GTRUE } else { glib_sys::GFALSE }, glib_sys::GFALSE)
+}
+
+unsafe extern "C" fn glib_flush_dispatch_cb(gs: *mut glib_sys::GSource, _: glib_sys::GSourceFunc, _: glib_sys::gpointer) -> glib_sys::gboolean {
+    ffi_cb_wrapper(|| {
+        let ss: &GSourceFlushData = &*(gs as *const _);
+        let be = &*ss.be;
+        let (calls, cancels, io_dirs) = crate::mainloop::take_queued();
+        for (cbid, cbk, p) in calls {
+            crate::mainloop::push_queued(|cbid, cbk, p| be.push(cbid, cbk, p), cbid, cbk, p);
+        }
+        for cbid in cancels { be.cancel(cbid); }
+        for (cbid, dir) in io_dirs { be.set_io_direction(cbid, dir); }
+        be.remove_finished();
+        if crate::mainloop::has_unhandled_panics() { crate::mainloop::handle_panics(false) }
+    }, ());
+    glib_sys::GTRUE
 }
 
 unsafe extern "C" fn glib_source_finalize_cb(gs: *mut glib_sys::GSource) {
@@ -77,7 +126,7 @@ unsafe extern "C" fn glib_source_dispatch_cb(gs: *mut glib_sys::GSource, _: glib
     ffi_cb_wrapper(|| {
         let ss: &mut GSourceIOData = &mut *(gs as *mut _);
         let cond = glib_sys::g_source_query_unix_fd(gs, ss.tag);
-        let dir = gio_to_dir(cond);
+        let dir = gio_to_event(cond);
 
         let r = false;
         if let Some(mut cb_data) = &ss.cb_data {
@@ -88,11 +137,11 @@ unsafe extern "C" fn glib_source_dispatch_cb(gs: *mut glib_sys::GSource, _: glib
    }, glib_sys::GFALSE)
 }
 
-fn cbdata_call(cb_data: &CbData, dir: Option<Result<IODirection, std::io::Error>>) -> bool {
+fn cbdata_call(cb_data: &CbData, dir: Option<Result<IOEvent, std::io::Error>>) -> bool {
     if let Some(ref mut kind) = *cb_data.kind.borrow_mut() {
-        if kind.call_mut(dir) { return true; }
+        if kind.call_mut(cb_data.cbid, dir) { return true; }
     };
-    cb_data.kind.borrow_mut().take().map(|kind| { kind.post_call_mut(); });
+    cb_data.kind.borrow_mut().take().map(|kind| { kind.post_call_mut(cb_data.cbid); });
     FINISHED_TLS.with(|f| { f.borrow_mut().push(cb_data.cbid); });
     false
 }
@@ -100,20 +149,28 @@ fn cbdata_call(cb_data: &CbData, dir: Option<Result<IODirection, std::io::Error>
 fn dir_to_gio(d: IODirection) -> glib_sys::GIOCondition {
     glib_sys::G_IO_HUP + glib_sys::G_IO_ERR + match d {
         IODirection::None => 0,
-        IODirection::Read => glib_sys::G_IO_IN,
+        IODirection::Read => glib_sys::G_IO_IN + glib_sys::G_IO_PRI,
         IODirection::Write => glib_sys::G_IO_OUT,
-        IODirection::Both => glib_sys::G_IO_IN + glib_sys::G_IO_OUT,
+        IODirection::Both => glib_sys::G_IO_IN + glib_sys::G_IO_PRI + glib_sys::G_IO_OUT,
     }
 }
 
-fn gio_to_dir(cond: glib_sys::GIOCondition) -> Result<IODirection, std::io::Error> {
-    const BOTH: c_uint = glib_sys::G_IO_IN + glib_sys::G_IO_OUT;
-    match cond {
-       0 => Ok(IODirection::None),
-       glib_sys::G_IO_IN => Ok(IODirection::Read),
-       glib_sys::G_IO_OUT => Ok(IODirection::Write),
-       BOTH => Ok(IODirection::Both),
-       _ => unimplemented!(),
+fn gio_to_event(cond: glib_sys::GIOCondition) -> Result<IOEvent, std::io::Error> {
+    if cond & glib_sys::G_IO_NVAL != 0 { return Err(std::io::Error::from_raw_os_error(libc::EBADF)) };
+    Ok(IOEvent {
+        readable: cond & glib_sys::G_IO_IN != 0,
+        writable: cond & glib_sys::G_IO_OUT != 0,
+        hangup: cond & glib_sys::G_IO_HUP != 0,
+        error: cond & glib_sys::G_IO_ERR != 0,
+        priority: cond & glib_sys::G_IO_PRI != 0,
+    })
+}
+
+fn priority_to_glib(p: Priority) -> std::os::raw::c_int {
+    match p {
+        Priority::High => glib_sys::G_PRIORITY_HIGH,
+        Priority::Default => glib_sys::G_PRIORITY_DEFAULT,
+        Priority::Low => glib_sys::G_PRIORITY_DEFAULT_IDLE,
     }
 }
 
@@ -159,44 +216,100 @@ impl SendFnOnce for Sender {
 impl Drop for Backend<'_> {
     fn drop(&mut self) {
         FINISHED_TLS.with(|f| { f.borrow_mut().clear(); }); 
-        self.cb_map.borrow_mut().clear();
-        unsafe { glib_sys::g_main_context_unref(self.ctx) }
+        self.0.cb_map.borrow_mut().clear();
+        self.0.flush.take();
+        unsafe { glib_sys::g_main_context_unref(self.0.ctx) }
     }
 }
 
 impl<'a> Backend<'a> {
-    pub (crate) fn new() -> Result<(Self, Box<SendFnOnce>), MainLoopError> { 
-        let be = Backend {
-            ctx: unsafe { glib_sys::g_main_context_new() }, 
+    pub (crate) fn new() -> Result<(Self, Box<dyn SendFnOnce>), MainLoopError> { 
+        unsafe {
+            let ctx = glib_sys::g_main_context_new();
+            let r = Self::with_context(ctx);
+            glib_sys::g_main_context_unref(ctx);
+            r
+        }
+    }
+
+    // Uses the thread default context, which is the global default context unless someone has pushed another one.
+    pub (crate) fn with_default_context() -> Result<(Self, Box<dyn SendFnOnce>), MainLoopError> {
+        unsafe {
+            let ctx = glib_sys::g_main_context_ref_thread_default();
+            let r = Self::with_context(ctx);
+            glib_sys::g_main_context_unref(ctx);
+            r
+        }
+    }
+
+    // Takes a new reference to the context.
+    pub (crate) unsafe fn with_context(ctx: *mut glib_sys::GMainContext) -> Result<(Self, Box<dyn SendFnOnce>), MainLoopError> {
+        if ctx.is_null() { return Err(MainLoopError::Unsupported) };
+        let mut be = Box::new(BeInternal {
+            ctx: glib_sys::g_main_context_ref(ctx),
             cb_map: Default::default(),
-        };
+            flush: None,
+        });
         FINISHED_TLS.with(|stls| {
             *stls.borrow_mut() = Default::default();
         });
+        let s = glib_sys::g_source_new(&G_SOURCE_FLUSH_FUNCS as *const _ as *mut _, mem::size_of::<GSourceFlushData>() as u32);
+        let ss: &mut GSourceFlushData = &mut *(s as *mut _);
+        ss.be = &*be as *const BeInternal as *const _;
+        glib_sys::g_source_set_priority(s, glib_sys::G_PRIORITY_HIGH);
+        glib_sys::g_source_attach(s, be.ctx);
+        be.flush = Some(GSourceRef(NonNull::new(s).unwrap()));
         let sender = Sender(unsafe { glib_sys::g_main_context_ref(be.ctx) }); 
-        Ok((be, Box::new(sender)))
+        Ok((Backend(be), Box::new(sender)))
     }
 
     pub fn run_one(&self, wait: bool) -> bool {
         let w = if wait { glib_sys::GTRUE } else { glib_sys::GFALSE };
-        let r = unsafe { glib_sys::g_main_context_iteration(self.ctx, w) != glib_sys::GFALSE };
-        FINISHED_TLS.with(|f| {
-            for cbid in f.borrow_mut().drain(..) {
-                self.cb_map.borrow_mut().remove(&cbid);
-            };
-        });
+        let r = unsafe { glib_sys::g_main_context_iteration(self.0.ctx, w) != glib_sys::GFALSE };
+        self.0.remove_finished();
         r
     }
 
-    pub (crate) fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
+    pub (crate) fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> { self.0.cancel(cbid) }
+
+    pub (crate) fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> bool { self.0.set_io_direction(cbid, dir) }
+
+    pub (crate) fn push(&self, cbid: CbId, cb: CbKind<'a>, priority: Priority) -> Result<(), MainLoopError> { self.0.push(cbid, cb, priority) }
+}
+
+impl<'a> BeInternal<'a> {
+    fn remove_finished(&self) {
+        // Removing a callback can finalize its source, which adds to FINISHED_TLS
+        let finished: Vec<_> = FINISHED_TLS.with(|f| f.borrow_mut().drain(..).collect());
+        for cbid in finished {
+            self.cb_map.borrow_mut().remove(&cbid);
+        }
+    }
+
+    fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
         self.cb_map.borrow_mut().remove(&cbid)
         .and_then(|s| { s.kind.borrow_mut().take() })
     }
 
-    pub (crate) fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+    fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> bool {
+        let cb_map = self.cb_map.borrow();
+        let cb_data = match cb_map.get(&cbid) { Some(x) => x, None => return false };
+        if cb_data.kind.borrow().as_ref().and_then(|k| k.handle()).is_none() { return false };
+        unsafe {
+            let s = cb_data.gsource.0.as_ptr();
+            let ss: &mut GSourceIOData = &mut *(s as *mut _);
+            glib_sys::g_source_modify_unix_fd(s, ss.tag, dir_to_gio(dir));
+        }
+        true
+    }
+
+    fn push(&self, cbid: CbId, cb: CbKind<'a>, priority: Priority) -> Result<(), MainLoopError> {
         let mut tag = None;
+        let cb_idle = cb.is_idle();
         let s = unsafe { 
-            if let Some((handle, direction)) = cb.handle() {
+            if let Some(sig) = cb.unix_signal() {
+                glib_sys::g_unix_signal_source_new(sig.signum())
+            } else if let Some((handle, direction)) = cb.handle() {
                 let s = glib_sys::g_source_new(&G_SOURCE_FUNCS as *const _ as *mut _, mem::size_of::<GSourceIOData>() as u32);
                 tag = Some(glib_sys::g_source_add_unix_fd(s, handle.0, dir_to_gio(direction)));
                 s
@@ -224,7 +337,9 @@ impl<'a> Backend<'a> {
                 glib_sys::g_source_set_callback(s, Some(glib_cb), x.as_ptr() as *mut _ as *mut _, None);
             }
 
-            glib_sys::g_source_set_priority(s, glib_sys::G_PRIORITY_DEFAULT);
+            // Idle callbacks run only when nothing else is ready.
+            let priority = if cb_idle { glib_sys::G_PRIORITY_LOW } else { priority_to_glib(priority) };
+            glib_sys::g_source_set_priority(s, priority);
             glib_sys::g_source_attach(s, self.ctx);
         }
         Ok(())
@@ -2,8 +2,6 @@
 //!
 //! See README.md for an introduction and some examples.
 
-#![cfg_attr(feature = "futures", feature(async_await))]
-
 // Because not all backends use everything in the common code
 #![allow(unused_variables)]
 // #![allow(unused_imports)]
@@ -22,26 +20,87 @@ mod winmsg;
 #[cfg(feature = "web")]
 mod web;
 
-#[cfg(not(any(feature = "win32", feature = "glib", feature = "web")))]
+#[cfg(feature = "epoll")]
+mod epoll;
+
+#[cfg(not(any(feature = "win32", feature = "glib", feature = "web", feature = "epoll")))]
 mod ruststd;
 
+#[cfg(all(unix, not(any(feature = "glib", feature = "web"))))]
+mod signal;
+
+#[cfg(unix)]
+mod unix;
+
 #[cfg(not(feature = "web"))]
 mod mainloop;
 
 #[cfg(not(feature = "web"))]
-pub use crate::mainloop::MainLoop;
+pub use crate::mainloop::{MainLoop, MainLoopHandle, PanicPolicy, RunUntil};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::thread::ThreadId;
 
 /// Possible error codes returned from the main loop API.
 #[derive(Debug)]
 pub enum MainLoopError {
+    /// There is already a main loop running on this thread.
     TooManyMainLoops,
+    /// There is no main loop running on this thread.
     NoMainLoop,
+    /// The main loop has already been dropped.
+    MainLoopDropped,
+    /// The main loop can no longer receive functions from other threads.
+    ChannelClosed,
+    /// The backend does not support this operation.
     Unsupported,
+    /// The duration is too long for the backend.
     DurationTooLong,
-    Other(Box<std::error::Error>),
+    /// A future did not finish in time.
+    TimedOut,
+    /// The backend failed to start watching a file descriptor or socket.
+    IoRegistration(std::io::Error),
+    Other(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::fmt::Display for MainLoopError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MainLoopError::TooManyMainLoops => write!(f, "a main loop is already running on this thread"),
+            MainLoopError::NoMainLoop => write!(f, "no main loop is running on this thread"),
+            MainLoopError::MainLoopDropped => write!(f, "the main loop has been dropped"),
+            MainLoopError::ChannelClosed => write!(f, "the main loop's channel is closed"),
+            MainLoopError::Unsupported => write!(f, "operation not supported by the main loop backend"),
+            MainLoopError::DurationTooLong => write!(f, "duration too long"),
+            MainLoopError::TimedOut => write!(f, "timed out"),
+            // The underlying errors are available through source()
+            MainLoopError::IoRegistration(_) => write!(f, "failed to register I/O with the main loop"),
+            MainLoopError::Other(_) => write!(f, "main loop error"),
+        }
+    }
+}
+
+impl std::error::Error for MainLoopError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MainLoopError::IoRegistration(e) => Some(e),
+            MainLoopError::Other(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+/// Priority of a callback, relative to other callbacks that are ready to run at the same time.
+///
+/// Corresponding platform specific priorities:
+/// * glib: G_PRIORITY_HIGH, G_PRIORITY_DEFAULT, G_PRIORITY_DEFAULT_IDLE
+/// * win32: priorities are ignored
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    High,
+    #[default]
+    Default,
+    Low,
 }
 
 /// Callback Id, can be used to cancel callback before its run.
@@ -68,7 +127,7 @@ pub struct CbHandle(pub i32);
 /*
 struct CbFuture<'a> {
     #[cfg(feature = "futures")]
-    future: Box<futures::Future<Output=()> + Unpin + 'a>,
+    future: Box<dyn std::future::Future<Output=()> + Unpin + 'a>,
     #[cfg(not(feature = "futures"))]
     future: &'a (),
     instant: Option<Instant>,
@@ -79,8 +138,12 @@ struct CbFuture<'a> {
 enum CbKind<'a> {
     Asap(BoxFnOnce<'a, ()>),
     After(BoxFnOnce<'a, ()>, Duration),
+    At(BoxFnOnce<'a, ()>, Instant),
     Interval(Box<dyn FnMut() -> bool + 'a>, Duration),
-    IO(Box<IOAble + 'a>),
+    Idle(Box<dyn FnMut() -> bool + 'a>),
+    IO(Box<dyn IOAble + 'a>),
+    #[cfg(feature = "glib")]
+    Signal(Box<dyn FnMut() -> bool + 'a>, Signal),
 //    Future(CbFuture<'a>),
 }
 
@@ -88,8 +151,23 @@ impl<'a> CbKind<'a> {
     // Constructors
     pub fn asap<F: FnOnce() + 'a>(f: F) -> Self { CbKind::Asap(BoxFnOnce::from(f)) }
     pub fn after<F: FnOnce() + 'a>(f: F, d: Duration) -> Self { CbKind::After(BoxFnOnce::from(f), d) }
+    pub fn at<F: FnOnce() + 'a>(f: F, i: Instant) -> Self { CbKind::At(BoxFnOnce::from(f), i) }
     pub fn interval<F: FnMut() -> bool + 'a>(f: F, d: Duration) -> Self { CbKind::Interval(Box::new(f), d) }
+    pub fn idle<F: FnMut() -> bool + 'a>(f: F) -> Self { CbKind::Idle(Box::new(f)) }
     pub fn io<IO: IOAble + 'a>(io: IO) -> Self { CbKind::IO(Box::new(io)) }
+    #[cfg(feature = "glib")]
+    pub fn signal<F: FnMut() -> bool + 'a>(f: F, s: Signal) -> Result<Self, MainLoopError> { Ok(CbKind::Signal(Box::new(f), s)) }
+    // Backends without native signal support get an I/O callback on a self-pipe instead.
+    #[cfg(all(unix, not(any(feature = "glib", feature = "web"))))]
+    pub fn signal<F: FnMut() -> bool + 'a>(f: F, s: Signal) -> Result<Self, MainLoopError> { Ok(CbKind::io(signal::SignalPipe::new(s, f)?)) }
+
+    #[cfg(feature = "glib")]
+    pub fn unix_signal(&self) -> Option<Signal> {
+        match self {
+            CbKind::Signal(_, s) => Some(*s),
+            _ => None,
+        }
+    }
 
     // Used to figure out which one it is
     pub fn duration(&self) -> Option<Duration> {
@@ -97,16 +175,36 @@ impl<'a> CbKind<'a> {
             CbKind::IO(_) => None,
             CbKind::Asap(_) => None,
             CbKind::After(_, d) => Some(*d),
+            CbKind::At(_, i) => Some(i.saturating_duration_since(Instant::now())),
             CbKind::Interval(_, d) => Some(*d),
+            CbKind::Idle(_) => None,
+            #[cfg(feature = "glib")]
+            CbKind::Signal(_, _) => None,
 //            CbKind::Future(f) => f.instant.map(|x| x - Instant::now()),
         }
     }
+    pub fn is_idle(&self) -> bool {
+        matches!(self, CbKind::Idle(_))
+    }
+
+    // Only set for callbacks scheduled at an absolute deadline.
+    pub fn instant(&self) -> Option<Instant> {
+        match self {
+            CbKind::At(_, i) => Some(*i),
+            _ => None,
+        }
+    }
     pub fn duration_millis(&self) -> Result<Option<u32>, MainLoopError> {
         if let Some(d) = self.duration() {
-            let m = (u32::max_value() / 1000) - 1;
+            let m = (u32::MAX / 1000) - 1;
             let s = d.as_secs();
             if s >= m as u64 { return Err(MainLoopError::DurationTooLong) }
-            Ok(Some((s as u32) * 1000 + d.subsec_millis()))
+            // Round upwards, so one-shot timers never fire before they are due
+            let ms = match self {
+                CbKind::Interval(_, _) => d.subsec_millis(),
+                _ => d.subsec_nanos().div_ceil(1_000_000),
+            };
+            Ok(Some((s as u32) * 1000 + ms))
         } else { Ok(None) } 
     }
 
@@ -115,17 +213,26 @@ impl<'a> CbKind<'a> {
             CbKind::IO(io) => Some((io.handle(), io.direction())),
             CbKind::Asap(_) => None,
             CbKind::After(_, _) => None,
+            CbKind::At(_, _) => None,
             CbKind::Interval(_, _) => None,
+            CbKind::Idle(_) => None,
+            #[cfg(feature = "glib")]
+            CbKind::Signal(_, _) => None,
 //            CbKind::Future(f) => f.handle,
         }
     }
 
     // If "false" is returned, please continue with making a call to post_call_mut.
-    pub (crate) fn call_mut(&mut self, io_dir: Option<Result<IODirection, std::io::Error>>) -> bool {
-        match self {
+    // A callback that panics returns "false".
+    pub (crate) fn call_mut(&mut self, cbid: CbId, io_dir: Option<Result<IOEvent, std::io::Error>>) -> bool {
+        mainloop::catch_panic(Some(cbid), || match self {
             CbKind::Interval(f, _) => f(),
+            CbKind::Idle(f) => { mainloop::idle_called(); f() },
+            #[cfg(feature = "glib")]
+            CbKind::Signal(f, _) => f(),
             CbKind::IO(io) => io.on_rw(io_dir.unwrap()),
             CbKind::After(_, _) => false,
+            CbKind::At(_, _) => false,
             CbKind::Asap(_) => false,
 /*            CbKind::Future(f) => {
                 #[cfg(feature = "futures")]
@@ -135,23 +242,31 @@ impl<'a> CbKind<'a> {
                 #[cfg(not(feature = "futures"))]
                 unreachable!()
             } */      
-        }
+        }, false)
     }
 
-    pub (crate) fn post_call_mut(self) {
-        match self {
+    pub (crate) fn post_call_mut(self, cbid: CbId) {
+        mainloop::catch_panic(Some(cbid), || match self {
             CbKind::After(f, _) => f.call(),
+            CbKind::At(f, _) => f.call(),
             CbKind::Asap(f) => f.call(),
             CbKind::Interval(_, _) => {},
+            CbKind::Idle(_) => {},
             CbKind::IO(_) => {},
+            #[cfg(feature = "glib")]
+            CbKind::Signal(_, _) => {},
 //            CbKind::Future(_) => {},
-        }
+        }, ())
     }
 }
 
-fn call_internal(cb: CbKind<'static>) -> Result<(), MainLoopError> { 
+fn call_internal(cb: CbKind<'static>) -> Result<CbId, MainLoopError> {
+    call_internal_with_priority(cb, Priority::Default)
+}
+
+fn call_internal_with_priority(cb: CbKind<'static>, p: Priority) -> Result<CbId, MainLoopError> {
     #[cfg(not(feature = "web"))]
-    let r = mainloop::call_internal(cb);
+    let r = mainloop::call_internal(cb, p);
 
     #[cfg(feature = "web")]
     let r = web::call_internal(cb);
@@ -166,11 +281,17 @@ fn call_internal(cb: CbKind<'static>) -> Result<(), MainLoopError> {
 /// * node.js: process.nextTick
 /// * web: Promise.resolve().then(...)
 /// * win32: PostMessage
-pub fn call_asap<F: FnOnce() + 'static>(f: F) -> Result<(), MainLoopError> {
+pub fn call_asap<F: FnOnce() + 'static>(f: F) -> Result<CbId, MainLoopError> {
     let cb = CbKind::asap(f);
     call_internal(cb)
 }
 
+/// Runs a function as soon as possible, before or after other callbacks depending on priority.
+pub fn call_asap_with_priority<F: FnOnce() + 'static>(p: Priority, f: F) -> Result<CbId, MainLoopError> {
+    let cb = CbKind::asap(f);
+    call_internal_with_priority(cb, p)
+}
+
 /// Runs a function once, after a specified duration.
 ///
 /// Corresponding platform specific APIs:
@@ -178,11 +299,25 @@ pub fn call_asap<F: FnOnce() + 'static>(f: F) -> Result<(), MainLoopError> {
 /// * node.js: setTimeout
 /// * web: window.setTimeout
 /// * win32: SetTimer
-pub fn call_after<F: FnOnce() + 'static>(d: Duration, f: F) -> Result<(), MainLoopError> {
+pub fn call_after<F: FnOnce() + 'static>(d: Duration, f: F) -> Result<CbId, MainLoopError> {
     let cb = CbKind::after(f, d);
     call_internal(cb)
 }
 
+/// Runs a function once, after a specified duration, before or after other callbacks depending on priority.
+pub fn call_after_with_priority<F: FnOnce() + 'static>(p: Priority, d: Duration, f: F) -> Result<CbId, MainLoopError> {
+    let cb = CbKind::after(f, d);
+    call_internal_with_priority(cb, p)
+}
+
+/// Runs a function once, at a specified instant.
+///
+/// The deadline is absolute, so it does not drift if the callback is scheduled late.
+pub fn call_at<F: FnOnce() + 'static>(i: Instant, f: F) -> Result<CbId, MainLoopError> {
+    let cb = CbKind::at(f, i);
+    call_internal(cb)
+}
+
 /// Runs a function at regular intervals
 ///
 /// Return "true" from the function to continue running or "false" to
@@ -193,17 +328,53 @@ pub fn call_after<F: FnOnce() + 'static>(d: Duration, f: F) -> Result<(), MainLo
 /// * node.js: setInterval
 /// * web: window.setInterval
 /// * win32: SetTimer
-pub fn call_interval<F: FnMut() -> bool + 'static>(d: Duration, f: F) -> Result<(), MainLoopError> {
+pub fn call_interval<F: FnMut() -> bool + 'static>(d: Duration, f: F) -> Result<CbId, MainLoopError> {
     let cb = CbKind::interval(f, d);
     call_internal(cb)
 }
 
+/// Cancels a callback scheduled on the current thread's main loop.
+///
+/// This can be called from inside any callback. The callback is removed from the
+/// main loop before the main loop's next iteration.
+#[cfg(not(feature = "web"))]
+pub fn cancel(cbid: CbId) -> Result<(), MainLoopError> {
+    mainloop::cancel_internal(cbid)
+}
+
+/// Runs a function when the main loop has nothing else to do.
+///
+/// The function is called only after all ready timers, I/O and thread messages
+/// have been handled. Return "true" from the function to continue running or
+/// "false" to remove the callback from the main loop.
+///
+/// Corresponding platform specific APIs:
+/// * glib: g_idle_add_full with G_PRIORITY_LOW
+/// * win32: when PeekMessage returns no messages
+#[cfg(not(feature = "web"))]
+pub fn call_idle<F: FnMut() -> bool + 'static>(f: F) -> Result<CbId, MainLoopError> {
+    let cb = CbKind::idle(f);
+    call_internal(cb)
+}
+
 /// Runs a function on another thread. The target thread must run a main loop.
 #[cfg(not(feature = "web"))]
 pub fn call_thread<F: FnOnce() + Send + 'static>(thread: ThreadId, f: F) -> Result<(), MainLoopError> {
     mainloop::call_thread_internal(thread, boxfnonce::SendBoxFnOnce::from(f)) 
 }
 
+/// Runs a function on another thread, and returns a receiver for its result.
+///
+/// The target thread must run a main loop. If the target main loop is dropped
+/// before the function is run, the receiver will return an error.
+#[cfg(not(feature = "web"))]
+pub fn call_thread_with_result<R, F>(thread: ThreadId, f: F) -> Result<std::sync::mpsc::Receiver<R>, MainLoopError>
+where R: Send + 'static, F: FnOnce() -> R + Send + 'static {
+    let (tx, rx) = std::sync::mpsc::channel();
+    call_thread(thread, move || { let _ = tx.send(f()); })?;
+    Ok(rx)
+}
+
 /// Selects whether to wait for a CbHandle to be available for reading, writing, or both.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum IODirection {
@@ -213,19 +384,48 @@ pub enum IODirection {
     Both,
 }
 
+/// What happened to a CbHandle. Several of these can happen at the same time.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct IOEvent {
+    /// The handle can be read from without blocking.
+    pub readable: bool,
+    /// The handle can be written to without blocking.
+    pub writable: bool,
+    /// The other end has hung up. There might still be data left to read.
+    pub hangup: bool,
+    /// An error is pending on the handle. The next read or write will report it.
+    pub error: bool,
+    /// Priority (out-of-band) data can be read.
+    pub priority: bool,
+}
+
+impl IOEvent {
+    /// Returns whether the handle is readable, writable, or both.
+    pub fn direction(&self) -> IODirection {
+        match (self.readable, self.writable) {
+            (false, false) => IODirection::None,
+            (true, false) => IODirection::Read,
+            (false, true) => IODirection::Write,
+            (true, true) => IODirection::Both,
+        }
+    }
+}
+
 /// Represents an object that can be read from and/or written to.
 pub trait IOAble {
     fn handle(&self) -> CbHandle;
 
     fn direction(&self) -> IODirection;
 
-    fn on_rw(&mut self, _: Result<IODirection, std::io::Error>) -> bool;
-    /* TODO: Handle Errors / hangup / etc */
+    /// Called when something happened to the handle.
+    ///
+    /// Return "false" to remove the object from the main loop.
+    fn on_rw(&mut self, _: Result<IOEvent, std::io::Error>) -> bool;
 }
 
 /// The most common I/O object is one from which you can read asynchronously.
 /// This is a simple convenience wrapper for that kind of I/O object.
-pub struct IOReader<IO, F: FnMut(&mut IO, Result<IODirection, std::io::Error>)>{
+pub struct IOReader<IO, F: FnMut(&mut IO, Result<IOEvent, std::io::Error>)>{
     pub io: IO,
     pub f: F,
 }
@@ -233,12 +433,12 @@ pub struct IOReader<IO, F: FnMut(&mut IO, Result<IODirection, std::io::Error>)>{
 #[cfg(unix)]
 impl<IO, F> IOAble for IOReader<IO, F>
 where IO: std::os::unix::io::AsRawFd,
-      F: FnMut(&mut IO, Result<IODirection, std::io::Error>)
+      F: FnMut(&mut IO, Result<IOEvent, std::io::Error>)
 {
     fn handle(&self) -> CbHandle { CbHandle(self.io.as_raw_fd()) }
 
     fn direction(&self) -> IODirection { IODirection::Read }
-    fn on_rw(&mut self, r: Result<IODirection, std::io::Error>) -> bool {
+    fn on_rw(&mut self, r: Result<IOEvent, std::io::Error>) -> bool {
         (self.f)(&mut self.io, r);
         true
     }
@@ -247,23 +447,79 @@ where IO: std::os::unix::io::AsRawFd,
 #[cfg(windows)]
 impl<IO, F> IOAble for IOReader<IO, F>
 where IO: std::os::windows::io::AsRawSocket,
-      F: FnMut(&mut IO, Result<IODirection, std::io::Error>)
+      F: FnMut(&mut IO, Result<IOEvent, std::io::Error>)
 {
     fn handle(&self) -> CbHandle { CbHandle(self.io.as_raw_socket()) }
 
     fn direction(&self) -> IODirection { IODirection::Read }
-    fn on_rw(&mut self, r: Result<IODirection, std::io::Error>) -> bool {
+    fn on_rw(&mut self, r: Result<IOEvent, std::io::Error>) -> bool {
         (self.f)(&mut self.io, r);
         true
     }
 }
 
 /// Calls IOAble's callbacks when there is data to be read or written.
-pub fn call_io<IO: IOAble + 'static>(io: IO) -> Result<(), MainLoopError> {
+pub fn call_io<IO: IOAble + 'static>(io: IO) -> Result<CbId, MainLoopError> {
     let cb = CbKind::io(io);
     call_internal(cb)
 }
 
+/// A unix signal that can be handled by the main loop, see `call_signal`.
+#[cfg(unix)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Signal {
+    /// SIGHUP
+    Hangup,
+    /// SIGINT, e g Ctrl-C in a terminal
+    Interrupt,
+    /// SIGTERM
+    Terminate,
+    /// SIGUSR1
+    User1,
+    /// SIGUSR2
+    User2,
+    /// SIGWINCH
+    WindowChange,
+}
+
+#[cfg(unix)]
+impl Signal {
+    /// The platform's number for the signal.
+    pub fn signum(&self) -> std::os::raw::c_int {
+        match self {
+            Signal::Hangup => libc::SIGHUP,
+            Signal::Interrupt => libc::SIGINT,
+            Signal::Terminate => libc::SIGTERM,
+            Signal::User1 => libc::SIGUSR1,
+            Signal::User2 => libc::SIGUSR2,
+            Signal::WindowChange => libc::SIGWINCH,
+        }
+    }
+}
+
+/// Runs a function on the current thread's main loop whenever the process receives a signal.
+///
+/// The signal's default action (e g terminating the process) is replaced for as long as the callback
+/// is registered. Signals that arrive close together may result in a single call.
+/// Return "true" from the function to continue running or "false" to remove the callback from the main loop.
+///
+/// Corresponding platform specific APIs:
+/// * glib: g_unix_signal_source_new
+/// * other unix backends: a signal handler writing to a self-pipe
+#[cfg(all(unix, not(feature = "web")))]
+pub fn call_signal<F: FnMut() -> bool + 'static>(s: Signal, f: F) -> Result<CbId, MainLoopError> {
+    let cb = CbKind::signal(f, s)?;
+    call_internal(cb)
+}
+
+/// Changes whether an I/O callback on the current thread's main loop waits for reading, writing, or both.
+///
+/// This can be called from inside any callback. The change takes effect before the main loop's next iteration.
+#[cfg(not(feature = "web"))]
+pub fn set_io_direction(cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+    mainloop::set_io_direction_internal(cbid, dir)
+}
+
 /// Terminates the currently running main loop.
 ///
 /// This function does nothing if the main loop is not running.
@@ -273,6 +529,23 @@ pub fn terminate() {
     mainloop::terminate();
 }
 
+/// Terminates the currently running main loop, and makes `MainLoop::run_with::<T>` return `value`.
+///
+/// The value can be e g an exit code or a `Result`. If the main loop has already been terminated,
+/// with or without a value, the value is dropped.
+///
+/// This function does nothing if the main loop is not running.
+/// This function does nothing with the "web" feature.
+pub fn terminate_with<T: std::any::Any>(value: T) {
+    #[cfg(not(feature = "web"))]
+    mainloop::terminate_with(value);
+    #[cfg(feature = "web")]
+    drop(value);
+}
+
 #[cfg(feature = "futures")]
 pub mod future;
 
+#[cfg(all(unix, not(feature = "web")))]
+pub mod process;
+
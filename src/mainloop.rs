@@ -4,19 +4,24 @@ use crate::glib::Backend;
 #[cfg(feature = "win32")]
 use crate::winmsg::Backend;
 
-#[cfg(not(any(feature = "win32", feature = "glib")))]
+#[cfg(feature = "epoll")]
+use crate::epoll::Backend;
+
+#[cfg(not(any(feature = "win32", feature = "glib", feature = "epoll")))]
 use crate::ruststd::Backend;
 
 use std::cell::{Cell, RefCell};
 use std::marker::PhantomData;
 use std::rc::Rc;
 use std::panic;
+use std::io;
 use std::any::Any;
-use std::time::Duration;
-use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::collections::HashMap;
 use std::thread::ThreadId;
-use crate::{CbKind, CbId, MainLoopError, IOAble};
+use crate::{CbKind, CbId, CbHandle, MainLoopError, IOAble, IODirection, IOEvent, Priority};
 use boxfnonce::SendBoxFnOnce;
 
 
@@ -25,50 +30,203 @@ struct MlTls {
     exists: Cell<bool>,
     terminated: Cell<bool>,
     running: Cell<bool>,
-    in_queue: RefCell<Vec<CbKind<'static>>>,
-    current_panic: RefCell<Option<Box<dyn Any + Send + 'static>>>,
+    in_queue: RefCell<Vec<(CbId, CbKind<'static>, Priority)>>,
+    cancel_queue: RefCell<Vec<CbId>>,
+    io_dir_queue: RefCell<Vec<(CbId, IODirection)>>,
+    panics: RefCell<Vec<(Option<CbId>, PanicPayload)>>,
+    panic_policy: RefCell<PanicPolicy>,
+    exit_value: RefCell<Option<Box<dyn Any>>>,
+    idle_called: Cell<bool>,
 }
 
+type PanicPayload = Box<dyn Any + Send + 'static>;
+
+/// What the main loop does when a callback panics.
+#[derive(Default)]
+pub enum PanicPolicy {
+    /// Resumes the panic from `run` or `run_one`, after the current iteration. This is the default.
+    ///
+    /// If a glib context is iterated by someone else, the panic cannot unwind through glib, so the
+    /// process is aborted instead.
+    #[default]
+    Propagate,
+    /// Prints the panic message to stderr, and continues running the main loop.
+    LogAndContinue,
+    /// Calls a function with the id of the callback that panicked and the panic payload, then continues
+    /// running the main loop. The id is None for functions sent from other threads.
+    Hook(Box<dyn FnMut(Option<CbId>, PanicPayload)>),
+}
+
+
 // Panic handling
 
 thread_local! {
     static ML_TLS: MlTls = Default::default();
 }
 
-pub (crate) fn ffi_cb_wrapper<R, F: FnOnce() -> R>(f: F, on_panic: R) -> R {
-    match panic::catch_unwind(panic::AssertUnwindSafe(|| { f() })) {
+// Catches a panic from a callback, so it can be handled according to the PanicPolicy.
+pub (crate) fn catch_panic<R, F: FnOnce() -> R>(cbid: Option<CbId>, f: F, on_panic: R) -> R {
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
         Ok(x) => x,
         Err(e) => {
             ML_TLS.with(|m| {
-                // We should never get a double panic, but if we do, let's ignore the info from the second one.
-                // Probably the info from the first one is the more helpful.
-                let _ = m.current_panic.try_borrow_mut().map(|mut cp| { *cp = Some(e); });
+                let _ = m.panics.try_borrow_mut().map(|mut p| { p.push((cbid, e)); });
             });
             on_panic
         }
     }
 }
 
+pub (crate) fn ffi_cb_wrapper<R, F: FnOnce() -> R>(f: F, on_panic: R) -> R {
+    catch_panic(None, f, on_panic)
+}
+
+fn log_panic(cbid: Option<CbId>, e: &PanicPayload) {
+    let msg = e.downcast_ref::<&str>().copied()
+        .or_else(|| e.downcast_ref::<String>().map(|s| &**s))
+        .unwrap_or("Box<dyn Any>");
+    match cbid {
+        Some(cbid) => eprintln!("thin_main_loop: callback {:?} panicked: {}", cbid, msg),
+        None => eprintln!("thin_main_loop: callback panicked: {}", msg),
+    }
+}
+
+// Applies the PanicPolicy to the panics caught so far. Without can_unwind, Propagate aborts.
+pub (crate) fn handle_panics(can_unwind: bool) {
+    ML_TLS.with(|m| {
+        let panics: Vec<_> = m.panics.borrow_mut().drain(..).collect();
+        for (cbid, e) in panics {
+            match &mut *m.panic_policy.borrow_mut() {
+                PanicPolicy::Propagate if can_unwind => panic::resume_unwind(e),
+                PanicPolicy::Propagate => {
+                    log_panic(cbid, &e);
+                    std::process::abort();
+                },
+                PanicPolicy::LogAndContinue => log_panic(cbid, &e),
+                PanicPolicy::Hook(f) => f(cbid, e),
+            }
+        }
+    })
+}
+
+// Panics from a glib context iterated by someone else, since there is no run_wrapper to handle them.
+#[cfg(feature = "glib")]
+pub (crate) fn has_unhandled_panics() -> bool {
+    ML_TLS.with(|m| !m.running.get() && !m.panics.borrow().is_empty())
+}
+
+// Callback ids are unique across all main loops.
+static NEXT_CBID: AtomicU64 = AtomicU64::new(1);
+
+pub (crate) fn next_cbid() -> CbId { CbId(NEXT_CBID.fetch_add(1, Ordering::Relaxed)) }
+
 // Thread sends
 
 pub (crate) trait SendFnOnce: Send {
     fn send(&self, f: SendBoxFnOnce<'static, ()>) -> Result<(), MainLoopError>;
 }
 
+// Set to None when the main loop is dropped.
+type SharedSender = Arc<Mutex<Option<Box<dyn SendFnOnce>>>>;
+
 lazy_static! {
-    static ref THREAD_SENDER: Mutex<HashMap<ThreadId, Box<SendFnOnce>>> = Default::default();
+    static ref THREAD_SENDER: Mutex<HashMap<ThreadId, SharedSender>> = Default::default();
+}
+
+fn send_shared(sender: &SharedSender, f: SendBoxFnOnce<'static, ()>) -> Result<(), MainLoopError> {
+    let s = sender.lock().unwrap();
+    s.as_ref().ok_or(MainLoopError::MainLoopDropped)?.send(f)
 }
 
 pub (crate) fn call_thread_internal(thread: ThreadId, f: SendBoxFnOnce<'static, ()>) -> Result<(), MainLoopError> {
     let map = THREAD_SENDER.lock().unwrap();
     let sender = map.get(&thread).ok_or(MainLoopError::NoMainLoop)?;
-    sender.send(f)
+    send_shared(sender, f)
+}
+
+// Callbacks, cancellations and I/O direction changes from the free functions, not yet applied to the backend.
+type Queued = (Vec<(CbId, CbKind<'static>, Priority)>, Vec<CbId>, Vec<(CbId, IODirection)>);
+
+pub (crate) fn take_queued() -> Queued {
+    ML_TLS.with(|m| (
+        m.in_queue.borrow_mut().drain(..).collect(),
+        m.cancel_queue.borrow_mut().drain(..).collect(),
+        m.io_dir_queue.borrow_mut().drain(..).collect(),
+    ))
 }
 
-pub (crate) fn call_internal(cb: CbKind<'static>) -> Result<(), MainLoopError> {
+#[cfg(feature = "glib")]
+pub (crate) fn has_queued() -> bool {
+    ML_TLS.with(|m| !m.in_queue.borrow().is_empty() || !m.cancel_queue.borrow().is_empty() || !m.io_dir_queue.borrow().is_empty())
+}
+
+// Lets run_until_idle find out that nothing but idle callbacks were ready.
+pub (crate) fn idle_called() {
+    ML_TLS.with(|m| m.idle_called.set(true));
+}
+
+pub (crate) fn has_main_loop() -> bool {
+    ML_TLS.with(|m| m.exists.get())
+}
+
+// Keeps a queued I/O callback reachable, so it can be told if the backend fails to register it.
+struct QueuedIO(Rc<RefCell<Box<dyn IOAble>>>);
+
+impl IOAble for QueuedIO {
+    fn handle(&self) -> CbHandle { self.0.borrow().handle() }
+    fn direction(&self) -> IODirection { self.0.borrow().direction() }
+    fn on_rw(&mut self, r: Result<IOEvent, io::Error>) -> bool { self.0.borrow_mut().on_rw(r) }
+}
+
+// The free functions have already returned a CbId, so an I/O callback that cannot be registered
+// gets the error through on_rw instead, and is then dropped.
+pub (crate) fn push_queued<F>(push: F, cbid: CbId, cb: CbKind<'static>, p: Priority)
+where F: FnOnce(CbId, CbKind<'static>, Priority) -> Result<(), MainLoopError> {
+    let io = match cb {
+        CbKind::IO(io) => Rc::new(RefCell::new(io)),
+        // There is nobody to report the error to
+        cb => { let _ = push(cbid, cb, p); return; }
+    };
+    if let Err(e) = push(cbid, CbKind::IO(Box::new(QueuedIO(io.clone()))), p) {
+        let e = match e {
+            MainLoopError::IoRegistration(e) => e,
+            e => io::Error::other(e),
+        };
+        catch_panic(Some(cbid), || { io.borrow_mut().on_rw(Err(e)); }, ());
+    }
+}
+
+pub (crate) fn call_internal(cb: CbKind<'static>, p: Priority) -> Result<CbId, MainLoopError> {
+    let cbid = next_cbid();
+    call_internal_with_id(cbid, cb, p)?;
+    Ok(cbid)
+}
+
+fn call_internal_with_id(cbid: CbId, cb: CbKind<'static>, p: Priority) -> Result<(), MainLoopError> {
+    ML_TLS.with(|m| {
+        if !m.exists.get() { return Err(MainLoopError::NoMainLoop) }
+        m.in_queue.borrow_mut().push((cbid, cb, p));
+        Ok(())
+    })
+}
+
+pub (crate) fn cancel_internal(cbid: CbId) -> Result<(), MainLoopError> {
     ML_TLS.with(|m| {
         if !m.exists.get() { return Err(MainLoopError::NoMainLoop) }
-        m.in_queue.borrow_mut().push(cb);
+        let mut q = m.in_queue.borrow_mut();
+        if let Some(idx) = q.iter().position(|(id, _, _)| *id == cbid) {
+            q.remove(idx);
+        } else {
+            m.cancel_queue.borrow_mut().push(cbid);
+        }
+        Ok(())
+    })
+}
+
+pub (crate) fn set_io_direction_internal(cbid: CbId, dir: IODirection) -> Result<(), MainLoopError> {
+    ML_TLS.with(|m| {
+        if !m.exists.get() { return Err(MainLoopError::NoMainLoop) }
+        m.io_dir_queue.borrow_mut().push((cbid, dir));
         Ok(())
     })
 }
@@ -79,25 +237,118 @@ pub (crate) fn terminate() {
     });
 }
 
+pub (crate) fn terminate_with<T: Any>(value: T) {
+    ML_TLS.with(|m| {
+        // Already terminated, with or without a value
+        if m.terminated.replace(true) { return; }
+        *m.exit_value.borrow_mut() = Some(Box::new(value));
+    });
+}
+
+pub (crate) fn take_exit_value<T: Any>() -> Option<T> {
+    let v = ML_TLS.with(|m| m.exit_value.borrow_mut().take());
+    v.and_then(|v| v.downcast().ok()).map(|v| *v)
+}
+
+/// A handle to a main loop, which can be used to schedule callbacks from other threads.
+///
+/// Callbacks are run on the main loop's thread.
+#[derive(Clone)]
+pub struct MainLoopHandle {
+    sender: SharedSender,
+}
+
+impl MainLoopHandle {
+    /// Runs a function as soon as possible on the main loop's thread.
+    pub fn call_asap<F: FnOnce() + Send + 'static>(&self, f: F) -> Result<CbId, MainLoopError> {
+        let cbid = next_cbid();
+        send_shared(&self.sender, SendBoxFnOnce::from(move || {
+            let _ = call_internal_with_id(cbid, CbKind::asap(f), Priority::Default);
+        }))?;
+        Ok(cbid)
+    }
+
+    /// Runs a function once on the main loop's thread, after a specified duration.
+    pub fn call_after<F: FnOnce() + Send + 'static>(&self, d: Duration, f: F) -> Result<CbId, MainLoopError> {
+        let cbid = next_cbid();
+        send_shared(&self.sender, SendBoxFnOnce::from(move || {
+            let _ = call_internal_with_id(cbid, CbKind::after(f, d), Priority::Default);
+        }))?;
+        Ok(cbid)
+    }
+
+    /// Terminates the main loop.
+    pub fn terminate(&self) -> Result<(), MainLoopError> {
+        send_shared(&self.sender, SendBoxFnOnce::from(terminate))
+    }
+
+    /// Terminates the main loop, and makes `MainLoop::run_with` return a value.
+    pub fn terminate_with<T: Any + Send>(&self, value: T) -> Result<(), MainLoopError> {
+        send_shared(&self.sender, SendBoxFnOnce::from(move || terminate_with(value)))
+    }
+
+    /// Cancels a callback scheduled on the main loop.
+    pub fn cancel(&self, cbid: CbId) -> Result<(), MainLoopError> {
+        send_shared(&self.sender, SendBoxFnOnce::from(move || { let _ = cancel_internal(cbid); }))
+    }
+}
+
+/// A condition for `MainLoop::run_until`: either an `Instant`, or a function that returns true when done.
+///
+/// The type parameter only tells the implementations apart.
+pub trait RunUntil<M> {
+    /// Returns when to wake up the main loop to check the condition, if ever.
+    fn deadline(&self) -> Option<Instant> { None }
+    /// Returns true when the main loop should stop running.
+    fn done(&mut self) -> bool;
+}
+
+impl RunUntil<Instant> for Instant {
+    fn deadline(&self) -> Option<Instant> { Some(*self) }
+    fn done(&mut self) -> bool { Instant::now() >= *self }
+}
+
+impl<F: FnMut() -> bool> RunUntil<bool> for F {
+    fn done(&mut self) -> bool { self() }
+}
+
 pub struct MainLoop<'a> {
     backend: Backend<'a>,
-    next_id: Cell<CbId>,
+    sender: SharedSender,
     _z: PhantomData<Rc<()>>, // !Send, !Sync
 }
 
  
 impl<'a> MainLoop<'a> {
     pub fn terminate(&self) { terminate() }
+    pub fn terminate_with<T: Any>(&self, value: T) { terminate_with(value) }
     pub fn call_asap<F: FnOnce() + 'a>(&self, f: F) -> Result<CbId, MainLoopError> { self.push(CbKind::asap(f)) }
+    pub fn call_asap_with_priority<F: FnOnce() + 'a>(&self, p: Priority, f: F) -> Result<CbId, MainLoopError> { self.push_with_priority(CbKind::asap(f), p) }
     pub fn call_after<F: FnOnce() + 'a>(&self, d: Duration, f: F) -> Result<CbId, MainLoopError> { self.push(CbKind::after(f, d)) }
+    pub fn call_after_with_priority<F: FnOnce() + 'a>(&self, p: Priority, d: Duration, f: F) -> Result<CbId, MainLoopError> { self.push_with_priority(CbKind::after(f, d), p) }
+    pub fn call_at<F: FnOnce() + 'a>(&self, i: Instant, f: F) -> Result<CbId, MainLoopError> { self.push(CbKind::at(f, i)) }
     pub fn call_interval<F: FnMut() -> bool + 'a>(&self, d: Duration, f: F)  -> Result<CbId, MainLoopError> { self.push(CbKind::interval(f, d)) }
+    pub fn call_idle<F: FnMut() -> bool + 'a>(&self, f: F) -> Result<CbId, MainLoopError> { self.push(CbKind::idle(f)) }
     pub fn call_io<IO: IOAble + 'a>(&self, io: IO) -> Result<CbId, MainLoopError> { self.push(CbKind::io(io)) }
+    #[cfg(unix)]
+    pub fn call_signal<F: FnMut() -> bool + 'a>(&self, s: crate::Signal, f: F) -> Result<CbId, MainLoopError> { self.push(CbKind::signal(f, s)?) }
     pub fn cancel(&self, cbid: CbId) -> bool { self.backend.cancel(cbid).is_some() }
 
+    /// Changes whether an I/O callback waits for reading, writing, or both.
+    ///
+    /// Returns false if there is no I/O callback with that id.
+    pub fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> bool { self.backend.set_io_direction(cbid, dir) }
+
+    /// Returns a handle that can be used to schedule callbacks on this main loop from other threads.
+    pub fn handle(&self) -> MainLoopHandle { MainLoopHandle { sender: self.sender.clone() } }
+
     fn push(&self, cb: CbKind<'a>) -> Result<CbId, MainLoopError> {
-        let x = self.next_id.get();
-        self.next_id.set(CbId(x.0 + 1));
-        self.backend.push(x, cb)?;
+        self.push_with_priority(cb, Priority::Default)
+    }
+
+    fn push_with_priority(&self, cb: CbKind<'a>, p: Priority) -> Result<CbId, MainLoopError> {
+        let x = next_cbid();
+        self.backend.push(x, cb, p)?;
         Ok(x)
     }
 
@@ -105,22 +356,31 @@ impl<'a> MainLoop<'a> {
         ML_TLS.with(|m| {
             if m.terminated.get() { return false; }
             {
-                let mut q = m.in_queue.borrow_mut();
-                for cbk in q.drain(..) {
-                    self.push(cbk).unwrap(); // TODO: Should probably be reported better
+                let (calls, cancels, io_dirs) = take_queued();
+                for (cbid, cbk, p) in calls {
+                    push_queued(|cbid, cbk, p| self.backend.push(cbid, cbk, p), cbid, cbk, p);
+                }
+                for cbid in cancels {
+                    self.backend.cancel(cbid);
+                }
+                for (cbid, dir) in io_dirs {
+                    self.backend.set_io_direction(cbid, dir);
                 }
             }
             if m.running.get() { panic!("Reentrant call to MainLoop") }
             m.running.set(true);
             f();
             m.running.set(false);
-            if let Some(e) = m.current_panic.borrow_mut().take() {
-                panic::resume_unwind(e);
-            }
+            handle_panics(true);
             true
         })
     }
 
+    /// Sets what to do when a callback panics.
+    ///
+    /// A callback that panics is removed from the main loop, regardless of policy.
+    pub fn set_panic_policy(&mut self, p: PanicPolicy) { ML_TLS.with(|m| *m.panic_policy.borrow_mut() = p); }
+
     /// Runs the main loop until terminated.
     pub fn run(&mut self) {
         while self.run_wrapper(|| {
@@ -128,6 +388,63 @@ impl<'a> MainLoop<'a> {
         }) {}
     }
 
+    /// Runs the main loop until terminated, and returns the value passed to `terminate_with`.
+    ///
+    /// Returns None if the main loop was terminated some other way, or with a value of another type.
+    pub fn run_with<T: Any>(&mut self) -> Option<T> {
+        self.run();
+        take_exit_value()
+    }
+
+    /// Returns the value passed to `terminate_with`, if the main loop was terminated that way,
+    /// the value is of type `T`, and it has not been returned already.
+    ///
+    /// Useful together with `run_one` and `run_until`.
+    pub fn take_exit_value<T: Any>(&mut self) -> Option<T> { take_exit_value() }
+
+    /// Runs the main loop until a deadline has passed, or until a function returns true.
+    ///
+    /// A function is checked before every iteration of the main loop, so it should depend on
+    /// something that a callback changes.
+    ///
+    /// Returns false if the mainloop was terminated.
+    pub fn run_until<M, U: RunUntil<M>>(&mut self, mut u: U) -> bool {
+        let fired = Rc::new(Cell::new(true));
+        let mut timer = None;
+        let r = loop {
+            if u.done() { break true; }
+            // Make sure the main loop wakes up, also if the timer fires a little early.
+            if let Some(d) = u.deadline() {
+                if fired.replace(false) {
+                    let f = fired.clone();
+                    timer = self.call_at(d, move || f.set(true)).ok();
+                }
+            }
+            if !self.run_one(true) { break false; }
+        };
+        if let Some(t) = timer { self.cancel(t); }
+        r
+    }
+
+    /// Runs the main loop for a duration.
+    ///
+    /// Returns false if the mainloop was terminated.
+    pub fn run_for(&mut self, d: Duration) -> bool { self.run_until(Instant::now() + d) }
+
+    /// Runs the main loop until there are no callbacks ready to run, except idle callbacks.
+    ///
+    /// Idle callbacks run only when nothing else is ready, so at most one of them is called.
+    ///
+    /// Returns false if the mainloop was terminated.
+    pub fn run_until_idle(&mut self) -> bool {
+        loop {
+            let mut ran = false;
+            ML_TLS.with(|m| m.idle_called.set(false));
+            if !self.run_wrapper(|| { ran = self.backend.run_one(false); }) { return false; }
+            if !ran || ML_TLS.with(|m| m.idle_called.get()) { return true; }
+        }
+    }
+
     /// Runs the main loop once
     ///
     /// Returns false if the mainloop was terminated.
@@ -139,26 +456,59 @@ impl<'a> MainLoop<'a> {
 
     /// Creates a new main loop
     pub fn new() -> Result<Self, MainLoopError> {
+        Self::with_backend(Backend::new)
+    }
+
+    /// Creates a main loop that attaches its callbacks to an existing glib main context.
+    ///
+    /// This way the callbacks run while e g GTK or GStreamer iterates the context. This includes callbacks
+    /// scheduled through the free functions (e g `call_asap`), and therefore futures, which are added to
+    /// the context on its next iteration.
+    ///
+    /// # Safety
+    ///
+    /// `ctx` must be a valid GMainContext. The MainLoop takes a reference of its own to it.
+    #[cfg(feature = "glib")]
+    pub unsafe fn from_glib_context(ctx: *mut glib_sys::GMainContext) -> Result<Self, MainLoopError> {
+        Self::with_backend(|| Backend::with_context(ctx))
+    }
+
+    /// Creates a main loop that attaches its callbacks to the thread default glib main context.
+    ///
+    /// Unless something else has been pushed as the thread default context, this is the global
+    /// default context, i e the one GTK uses. See `from_glib_context` for details.
+    #[cfg(feature = "glib")]
+    pub fn with_default_context() -> Result<Self, MainLoopError> {
+        Self::with_backend(Backend::with_default_context)
+    }
+
+    fn with_backend<F>(f: F) -> Result<Self, MainLoopError>
+    where F: FnOnce() -> Result<(Backend<'a>, Box<dyn SendFnOnce>), MainLoopError> {
         ML_TLS.with(|m| {
             if m.exists.get() { return Err(MainLoopError::TooManyMainLoops) };
 
-            let (be, sender) = Backend::new()?;
+            let (be, sender) = f()?;
             let thread_id = std::thread::current().id();
+            let sender = Arc::new(Mutex::new(Some(sender)));
             {
                 let mut s = THREAD_SENDER.lock().unwrap();
                 if s.contains_key(&thread_id) { return Err(MainLoopError::TooManyMainLoops) };
-                s.insert(thread_id, sender);
+                s.insert(thread_id, sender.clone());
             }
 
             m.in_queue.borrow_mut().clear();
-            m.current_panic.borrow_mut().take();
+            m.cancel_queue.borrow_mut().clear();
+            m.io_dir_queue.borrow_mut().clear();
+            m.panics.borrow_mut().clear();
+            *m.panic_policy.borrow_mut() = Default::default();
+            m.exit_value.borrow_mut().take();
             m.terminated.set(false);
             m.running.set(false);
             m.exists.set(true);
 
             Ok(MainLoop { 
                 backend: be,
-                next_id: Cell::new(CbId(1)),
+                sender,
                 _z: PhantomData 
             })
         })
@@ -170,6 +520,7 @@ impl Drop for MainLoop<'_> {
         ML_TLS.with(|m| { m.exists.set(false); });
         let thread_id = std::thread::current().id();
         THREAD_SENDER.lock().unwrap().remove(&thread_id);
+        self.sender.lock().unwrap().take();
     }
 }
 
@@ -182,16 +533,15 @@ fn borrowed() {
         ml.call_asap(|| { x = true; terminate(); }).unwrap();
         ml.run();
     }
-    assert_eq!(x, true);
+    assert!(x);
 }
 
 #[test]
 fn asap_static() {
     use std::rc::Rc;
 
-    let x;
+    let x = Rc::new(Cell::new(0));
     let mut ml = MainLoop::new().unwrap();
-    x = Rc::new(Cell::new(0));
     let xcl = x.clone();
     ml.call_asap(|| { 
         assert_eq!(x.get(), 0);
@@ -208,14 +558,12 @@ fn asap_static() {
 
 #[test]
 fn after() {
-    use std::time::Instant;
-    let x;
+    let x = Cell::new(false);
     let mut ml = MainLoop::new().unwrap();
-    x = Cell::new(false);
     let n = Instant::now();
     ml.call_after(Duration::from_millis(300), || { x.set(true); terminate(); }).unwrap();
     ml.run();
-    assert_eq!(x.get(), true);
+    assert!(x.get());
     let n2 = Instant::now();
     // Windows seems to have an accuracy of 10 - 20 ms
     if (n2 - n) < Duration::from_millis(280) {
@@ -223,9 +571,61 @@ fn after() {
     }
 }
 
+#[test]
+fn at() {
+    let x = Cell::new(false);
+    let n = Instant::now() + Duration::from_millis(200);
+    let mut ml = MainLoop::new().unwrap();
+    ml.call_asap(move || {
+        // Scheduling late must not delay the deadline
+        std::thread::sleep(Duration::from_millis(100));
+        crate::call_at(n, terminate).unwrap();
+    }).unwrap();
+    ml.call_at(n, || x.set(true)).unwrap();
+    ml.run();
+    assert!(x.get());
+    let n2 = Instant::now();
+    assert!(n2 >= n, "Early by {:?}", n - n2);
+    assert!(n2 < n + Duration::from_millis(80), "Late by {:?}", n2 - n);
+}
+
+#[test]
+fn priority() {
+    let x = RefCell::new(vec!());
+    let mut ml = MainLoop::new().unwrap();
+    let d = Duration::from_millis(10);
+    ml.call_after_with_priority(Priority::Low, d, || x.borrow_mut().push(3)).unwrap();
+    ml.call_after_with_priority(Priority::Default, d, || x.borrow_mut().push(2)).unwrap();
+    ml.call_after_with_priority(Priority::High, d, || x.borrow_mut().push(1)).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    ml.call_asap_with_priority(Priority::Low, || x.borrow_mut().push(4)).unwrap();
+    ml.call_after(Duration::from_millis(50), terminate).unwrap();
+    ml.run();
+    assert_eq!(*x.borrow(), vec!(1, 2, 3, 4));
+}
+
+#[test]
+fn idle() {
+    let x = RefCell::new(vec!());
+    let mut count = 0;
+    let mut ml = MainLoop::new().unwrap();
+    ml.call_idle(|| {
+        x.borrow_mut().push("idle");
+        count += 1;
+        if count == 2 { terminate(); }
+        true
+    }).unwrap();
+    ml.call_asap(|| {
+        x.borrow_mut().push("asap1");
+        crate::call_asap(|| {}).unwrap();
+    }).unwrap();
+    ml.call_asap(|| x.borrow_mut().push("asap2")).unwrap();
+    ml.run();
+    assert_eq!(*x.borrow(), vec!("asap1", "asap2", "idle", "idle"));
+}
+
 #[test]
 fn interval() {
-    use std::time::Instant;
     let mut x = 0;
     let mut y = 0;
     let n = Instant::now();
@@ -273,6 +673,48 @@ fn thread_test() {
     assert_eq!(x.load(Ordering::SeqCst), 1);
 }
 
+#[test]
+fn thread_result_test() {
+    use std::thread;
+    use std::sync::mpsc::channel;
+
+    let (tx, rx) = channel();
+    let t = thread::spawn(move || {
+        let mut ml = MainLoop::new().unwrap();
+        tx.send(thread::current().id()).unwrap();
+        ml.run();
+    });
+    let id = rx.recv().unwrap();
+    let r = crate::call_thread_with_result(id, move || thread::current().id()).unwrap();
+    assert_eq!(r.recv().unwrap(), id);
+    crate::call_thread(id, terminate).unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn handle_test() {
+    use std::thread;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let x = Arc::new(AtomicUsize::new(0));
+    let xcl = x.clone();
+    let handle = {
+        let mut ml = MainLoop::new().unwrap();
+        let handle = ml.handle();
+        thread::spawn(move || {
+            let id = handle.call_after(Duration::from_millis(50), || { panic!("This should have been cancelled!"); }).unwrap();
+            handle.cancel(id).unwrap();
+            handle.call_asap(move || { xcl.store(1, Ordering::SeqCst); }).unwrap();
+            handle.call_after(Duration::from_millis(100), terminate).unwrap();
+            handle
+        }).join().unwrap();
+        ml.run();
+        ml.handle()
+    };
+    assert_eq!(x.load(Ordering::SeqCst), 1);
+    assert!(matches!(handle.call_asap(|| {}), Err(MainLoopError::MainLoopDropped)));
+}
+
 #[cfg(any(feature = "glib", feature = "win32"))]
 #[test]
 fn io_test() {
@@ -316,6 +758,261 @@ fn io_test() {
     ml.run();
 }
 
+#[cfg(feature = "epoll")]
+#[test]
+fn io_register_fail_test() {
+    use crate::IOReader;
+
+    // epoll does not accept regular files
+    let f = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml")).unwrap();
+    let got = Rc::new(Cell::new(0));
+    let mut ml = MainLoop::new().unwrap();
+    let g = got.clone();
+    crate::call_io(IOReader { io: f, f: move |_: &mut std::fs::File, r: Result<crate::IOEvent, io::Error>| {
+        assert_eq!(r.unwrap_err().raw_os_error(), Some(libc::EPERM));
+        g.set(g.get() + 1);
+    }}).unwrap();
+    ml.run_one(false);
+    ml.run_one(false);
+    assert_eq!(got.get(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn io_pair_test() {
+    use std::os::unix::net::UnixStream;
+    use std::io::{Write, Read};
+    use crate::IOReader;
+
+    let (mut a, b) = UnixStream::pair().unwrap();
+    b.set_nonblocking(true).unwrap();
+    let mut reply = String::new();
+    {
+        let mut ml = MainLoop::new().unwrap();
+        let wr = IOReader { io: b, f: |io: &mut UnixStream, x| {
+            let ev = x.unwrap();
+            assert!(ev.readable || ev.hangup);
+            let r = io.read_to_string(&mut reply);
+            if let Ok(0) = r { terminate(); }
+        }};
+        ml.call_io(wr).unwrap();
+        ml.call_after(Duration::from_millis(50), move || {
+            a.write_all(b"Hello world").unwrap();
+        }).unwrap();
+        ml.run();
+    }
+    assert_eq!(reply, "Hello world");
+}
+
+#[cfg(unix)]
+#[test]
+fn io_starvation_test() {
+    use std::os::unix::net::UnixStream;
+    use std::io::Write;
+    use crate::IOReader;
+
+    fn again(count: Rc<Cell<u32>>) {
+        count.set(count.get() + 1);
+        if count.get() < 100_000 { crate::call_asap(move || again(count)).unwrap(); }
+    }
+
+    let (mut a, b) = UnixStream::pair().unwrap();
+    a.write_all(b"Hello").unwrap();
+    let count = Rc::new(Cell::new(0));
+    let c2 = count.clone();
+    let mut ml = MainLoop::new().unwrap();
+    ml.call_io(IOReader { io: b, f: move |_: &mut UnixStream, _| {
+        assert!(c2.get() < 100);
+        terminate();
+    }}).unwrap();
+    let c2 = count.clone();
+    ml.call_asap(move || again(c2)).unwrap();
+    ml.run();
+    assert!(count.get() < 100);
+}
+
+#[cfg(unix)]
+#[test]
+fn io_set_direction_test() {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+    use crate::{IOReader, IOEvent};
+
+    let (mut a, b) = UnixStream::pair().unwrap();
+    let id = Cell::new(None);
+    let mut events = vec!();
+    {
+        let mut ml = MainLoop::new().unwrap();
+        let cbid = ml.call_io(IOReader { io: b, f: |_: &mut UnixStream, ev: Result<IOEvent, _>| {
+            events.push(ev.unwrap());
+            if events.len() == 1 {
+                crate::set_io_direction(id.get().unwrap(), IODirection::Read).unwrap();
+                a.write_all(b"x").unwrap();
+            } else { terminate() }
+        }}).unwrap();
+        id.set(Some(cbid));
+        assert!(ml.set_io_direction(cbid, IODirection::Write));
+        assert!(!ml.set_io_direction(CbId(0), IODirection::Write));
+        ml.run();
+    }
+    assert!(events[0].writable && !events[0].readable);
+    assert!(events[1].readable && !events[1].writable);
+}
+
+#[cfg(feature = "glib")]
+#[test]
+fn glib_default_context_test() {
+    let mut x = false;
+    {
+        let ml = MainLoop::with_default_context().unwrap();
+        ml.call_asap(|| { x = true; }).unwrap();
+        // Someone else iterating the default context, e g GTK
+        unsafe { glib_sys::g_main_context_iteration(std::ptr::null_mut(), glib_sys::GFALSE) };
+    }
+    assert!(x);
+}
+
+#[cfg(feature = "glib")]
+#[test]
+fn glib_external_context_test() {
+    let x = Rc::new(Cell::new(0));
+    unsafe {
+        let ctx = glib_sys::g_main_context_new();
+        let _ml = MainLoop::from_glib_context(ctx).unwrap();
+        let x2 = x.clone();
+        crate::call_asap(move || {
+            x2.set(1);
+            crate::call_after(Duration::from_millis(10), move || x2.set(2)).unwrap();
+        }).unwrap();
+        // Someone else iterating the context
+        while x.get() < 2 { glib_sys::g_main_context_iteration(ctx, glib_sys::GTRUE); }
+        glib_sys::g_main_context_unref(ctx);
+    }
+}
+
+#[cfg(feature = "glib")]
+#[test]
+fn glib_external_panic_test() {
+    let n = Rc::new(Cell::new(0));
+    unsafe {
+        let ctx = glib_sys::g_main_context_new();
+        let mut ml = MainLoop::from_glib_context(ctx).unwrap();
+        let n2 = n.clone();
+        ml.set_panic_policy(PanicPolicy::Hook(Box::new(move |_, _| n2.set(n2.get() + 1))));
+        crate::call_asap(|| { panic!("Keep calm and carry on"); }).unwrap();
+        // Someone else iterating the context
+        while n.get() < 1 { glib_sys::g_main_context_iteration(ctx, glib_sys::GTRUE); }
+        drop(ml);
+        glib_sys::g_main_context_unref(ctx);
+    }
+    assert_eq!(n.get(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn signal_test() {
+    use crate::Signal;
+
+    let mut count = 0;
+    {
+        let mut ml = MainLoop::new().unwrap();
+        ml.call_signal(Signal::User1, || {
+            count += 1;
+            if count == 2 { terminate() };
+            true
+        }).unwrap();
+        ml.call_asap(|| unsafe { libc::raise(libc::SIGUSR1); }).unwrap();
+        ml.call_after(Duration::from_millis(50), || unsafe { libc::raise(libc::SIGUSR1); }).unwrap();
+        ml.run();
+    }
+    assert_eq!(count, 2);
+}
+
+#[cfg(unix)]
+#[test]
+fn io_hangup_test() {
+    use std::os::unix::net::UnixStream;
+    use crate::{IOEvent, IODirection};
+
+    struct Hangup(UnixStream, Vec<IOEvent>);
+    impl IOAble for &mut Hangup {
+        fn handle(&self) -> crate::CbHandle { crate::CbHandle(std::os::unix::io::AsRawFd::as_raw_fd(&self.0)) }
+        fn direction(&self) -> IODirection { IODirection::Read }
+        fn on_rw(&mut self, ev: Result<IOEvent, std::io::Error>) -> bool {
+            let ev = ev.unwrap();
+            self.1.push(ev);
+            if ev.hangup { terminate(); }
+            !ev.hangup
+        }
+    }
+
+    let (a, b) = UnixStream::pair().unwrap();
+    let mut h = Hangup(b, vec!());
+    {
+        let mut ml = MainLoop::new().unwrap();
+        ml.call_io(&mut h).unwrap();
+        ml.call_after(Duration::from_millis(50), move || drop(a)).unwrap();
+        ml.run();
+    }
+    assert!(h.1.last().unwrap().hangup);
+}
+
+#[test]
+fn run_until_test() {
+    let count = Cell::new(0);
+    let mut ml = MainLoop::new().unwrap();
+    ml.call_interval(Duration::from_millis(10), || { count.set(count.get() + 1); true }).unwrap();
+    let now = Instant::now();
+    assert!(ml.run_for(Duration::from_millis(35)));
+    assert!(now.elapsed() >= Duration::from_millis(35));
+    // More if the thread was descheduled for a while
+    let c = count.get();
+    assert!(c >= 3);
+    assert!(ml.run_until(|| count.get() == c + 2));
+    assert_eq!(count.get(), c + 2);
+
+    let idle = Rc::new(Cell::new(0));
+    let idle2 = idle.clone();
+    ml.call_asap(move || { crate::call_asap(move || idle2.set(2)).unwrap(); }).unwrap();
+    assert!(ml.run_until_idle());
+    assert_eq!(idle.get(), 2);
+
+    // Lazy work that never finishes must not keep run_until_idle running
+    let lazy = Rc::new(Cell::new(0));
+    let lazy2 = lazy.clone();
+    ml.call_idle(move || { lazy2.set(lazy2.get() + 1); true }).unwrap();
+    let idle3 = idle.clone();
+    ml.call_asap(move || idle3.set(3)).unwrap();
+    assert!(ml.run_until_idle());
+    assert_eq!(idle.get(), 3);
+    assert!(lazy.get() <= 1);
+    ml.call_asap(terminate).unwrap();
+    assert!(!ml.run_until_idle());
+    assert!(!ml.run_for(Duration::from_millis(10)));
+}
+
+#[test]
+fn terminate_with_test() {
+    {
+        let mut ml = MainLoop::new().unwrap();
+        ml.call_after(Duration::from_millis(10), || crate::terminate_with(3i32)).unwrap();
+        ml.call_after(Duration::from_millis(10), || crate::terminate_with(4i32)).unwrap();
+        assert_eq!(ml.run_with::<i32>(), Some(3));
+    }
+    {
+        let mut ml = MainLoop::new().unwrap();
+        ml.call_asap(|| { terminate(); crate::terminate_with(3i32); }).unwrap();
+        assert_eq!(ml.run_with::<i32>(), None);
+    }
+    let mut ml = MainLoop::new().unwrap();
+    let handle = ml.handle();
+    std::thread::spawn(move || {
+        handle.terminate_with(Err::<(), _>(MainLoopError::TimedOut)).unwrap();
+    });
+    let r = ml.run_with::<Result<(), MainLoopError>>().unwrap();
+    assert!(matches!(r, Err(MainLoopError::TimedOut)));
+}
+
 #[test]
 fn panic_inside_cb() {
     let mut ml = MainLoop::new().unwrap();
@@ -326,13 +1023,62 @@ fn panic_inside_cb() {
     assert_eq!(*zstr, "Keep calm and carry on");
 }
 
+#[test]
+fn panic_policy() {
+    {
+        let mut ml = MainLoop::new().unwrap();
+        ml.set_panic_policy(PanicPolicy::LogAndContinue);
+        ml.call_asap(|| { panic!("Keep calm and carry on"); }).unwrap();
+        ml.call_asap(terminate).unwrap();
+        ml.run();
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut ml = MainLoop::new().unwrap();
+    ml.set_panic_policy(PanicPolicy::Hook(Box::new(move |id, e| {
+        tx.send((id, *e.downcast::<&str>().unwrap())).unwrap();
+    })));
+    let id1 = ml.call_after(Duration::from_millis(10), || { panic!("after"); }).unwrap();
+    let id2 = ml.call_interval(Duration::from_millis(20), || { panic!("interval"); }).unwrap();
+    ml.call_after(Duration::from_millis(100), terminate).unwrap();
+    ml.run();
+    let r: Vec<_> = rx.try_iter().collect();
+    assert_eq!(r, vec!((Some(id1), "after"), (Some(id2), "interval")));
+}
+
+#[test]
+fn error_test() {
+    use std::error::Error;
+    fn is_send_sync<T: Error + Send + Sync + 'static>(_: &T) {}
+    let e = MainLoopError::IoRegistration(std::io::Error::from_raw_os_error(9));
+    is_send_sync(&e);
+    assert_eq!(e.to_string(), "failed to register I/O with the main loop");
+    assert!(e.source().unwrap().downcast_ref::<std::io::Error>().is_some());
+    assert_eq!(MainLoopError::NoMainLoop.to_string(), "no main loop is running on this thread");
+    assert!(MainLoopError::NoMainLoop.source().is_none());
+}
+
 #[test]
 fn cancel_cb() {
     let mut ml = MainLoop::new().unwrap();
     let id = ml.call_asap(|| { panic!("This should have been cancelled!"); }).unwrap();
     ml.call_after(Duration::from_millis(50), terminate).unwrap();
-    assert_eq!(ml.cancel(id), true);
-    assert_eq!(ml.cancel(id), false);
+    assert!(ml.cancel(id));
+    assert!(!ml.cancel(id));
+    ml.run();
+}
+
+#[test]
+fn cancel_static() {
+    let mut ml = MainLoop::new().unwrap();
+    ml.call_asap(|| {
+        let id = crate::call_after(Duration::from_millis(50), || { panic!("This should have been cancelled!"); }).unwrap();
+        crate::call_after(Duration::from_millis(25), move || { crate::cancel(id).unwrap(); }).unwrap();
+        // Cancelling before the callback has reached the backend works too
+        let id2 = crate::call_asap(|| { panic!("This should have been cancelled!"); }).unwrap();
+        crate::cancel(id2).unwrap();
+        crate::call_after(Duration::from_millis(100), terminate).unwrap();
+    }).unwrap();
     ml.run();
 }
 
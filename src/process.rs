@@ -0,0 +1,189 @@
+//! Running child processes without blocking the main loop (unix only).
+//!
+//! The child's stdout and stderr are read through the main loop, and its exit status
+//! is reported once it has exited and both pipes are closed.
+//!
+//! On Linux, the exit is detected through a pidfd. Elsewhere (or on kernels older than 5.3),
+//! the child is polled every 50 milliseconds. This includes the glib backend: glib's child watch
+//! is not used, since it reaps the child behind the back of `std::process::Child`, and neither is
+//! SIGCHLD, since a process-wide handler could interfere with other code waiting for children.
+
+use std::cell::{Cell, RefCell};
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::rc::Rc;
+use std::time::Duration;
+use crate::{CbHandle, IOAble, IODirection, IOEvent, MainLoopError};
+use crate::unix::set_nonblocking;
+
+/// Which of the child's output pipes data was read from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Pipe {
+    Stdout,
+    Stderr,
+}
+
+type DataFn = Box<dyn FnMut(Pipe, &[u8])>;
+type ExitFn = Box<dyn FnOnce(io::Result<ExitStatus>)>;
+
+struct Shared {
+    child: RefCell<Child>,
+    open: Cell<usize>,
+    status: RefCell<Option<io::Result<ExitStatus>>>,
+    on_data: RefCell<DataFn>,
+    on_exit: RefCell<Option<ExitFn>>,
+}
+
+impl Shared {
+    // Calls on_exit if the child has exited and all pipes are closed.
+    fn check_done(&self) {
+        if self.open.get() > 0 { return; }
+        let status = match self.status.borrow_mut().take() { Some(s) => s, None => return };
+        let f = self.on_exit.borrow_mut().take();
+        if let Some(f) = f { f(status) }
+    }
+
+    // Returns false when the child has exited.
+    fn try_wait(&self) -> bool {
+        let r = self.child.borrow_mut().try_wait();
+        let status = match r {
+            Ok(None) => return true,
+            Ok(Some(s)) => Ok(s),
+            Err(e) => Err(e),
+        };
+        *self.status.borrow_mut() = Some(status);
+        self.check_done();
+        false
+    }
+}
+
+struct PipeReader<R> {
+    pipe: Pipe,
+    io: R,
+    shared: Rc<Shared>,
+}
+
+impl<R: Read + AsRawFd> IOAble for PipeReader<R> {
+    fn handle(&self) -> CbHandle { CbHandle(self.io.as_raw_fd()) }
+    fn direction(&self) -> IODirection { IODirection::Read }
+    fn on_rw(&mut self, _: Result<IOEvent, io::Error>) -> bool {
+        let mut buf = [0u8; 4096];
+        loop {
+            match self.io.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => (self.shared.on_data.borrow_mut())(self.pipe, &buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(_) => break,
+            }
+        }
+        // End of file or error, so the pipe is done
+        self.shared.open.set(self.shared.open.get() - 1);
+        self.shared.check_done();
+        false
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct PidFd(RawFd, Rc<Shared>);
+
+#[cfg(target_os = "linux")]
+impl Drop for PidFd {
+    fn drop(&mut self) { unsafe { libc::close(self.0); } }
+}
+
+#[cfg(target_os = "linux")]
+impl IOAble for PidFd {
+    fn handle(&self) -> CbHandle { CbHandle(self.0) }
+    fn direction(&self) -> IODirection { IODirection::Read }
+    fn on_rw(&mut self, _: Result<IOEvent, io::Error>) -> bool { self.1.try_wait() }
+}
+
+#[cfg(target_os = "linux")]
+fn watch_exit(pid: u32, shared: &Rc<Shared>) -> Result<(), MainLoopError> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 { return poll_exit(shared) };
+    crate::call_io(PidFd(fd as RawFd, shared.clone()))?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch_exit(_: u32, shared: &Rc<Shared>) -> Result<(), MainLoopError> { poll_exit(shared) }
+
+fn poll_exit(shared: &Rc<Shared>) -> Result<(), MainLoopError> {
+    let shared = shared.clone();
+    crate::call_interval(Duration::from_millis(50), move || shared.try_wait())?;
+    Ok(())
+}
+
+/// Spawns a child process, and runs callbacks on the current thread's main loop for its output and exit.
+///
+/// The child's stdout and stderr are piped, and `on_data` is called whenever data arrives on either of them.
+/// `on_exit` is called with the exit status after the child has exited, and all output has been read.
+///
+/// Returns the child's process id.
+pub fn call_child<D, E>(cmd: &mut Command, on_data: D, on_exit: E) -> Result<u32, MainLoopError>
+where D: FnMut(Pipe, &[u8]) + 'static, E: FnOnce(io::Result<ExitStatus>) + 'static {
+    // Otherwise, nobody would wait for the child
+    if !crate::mainloop::has_main_loop() { return Err(MainLoopError::NoMainLoop) };
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+        .map_err(|e| MainLoopError::Other(e.into()))?;
+    let (stdout, stderr) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+    let pid = child.id();
+    let shared = Rc::new(Shared {
+        child: RefCell::new(child),
+        open: Cell::new(2),
+        status: RefCell::new(None),
+        on_data: RefCell::new(Box::new(on_data)),
+        on_exit: RefCell::new(Some(Box::new(on_exit))),
+    });
+    let r = (|| {
+        set_nonblocking(stdout.as_raw_fd())?;
+        set_nonblocking(stderr.as_raw_fd())?;
+        crate::call_io(PipeReader { pipe: Pipe::Stdout, io: stdout, shared: shared.clone() })?;
+        crate::call_io(PipeReader { pipe: Pipe::Stderr, io: stderr, shared: shared.clone() })?;
+        watch_exit(pid, &shared)
+    })();
+    if let Err(e) = r {
+        shared.on_exit.borrow_mut().take();
+        let mut child = shared.child.borrow_mut();
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(e);
+    }
+    Ok(pid)
+}
+
+#[test]
+fn child_test() {
+    use crate::MainLoop;
+
+    let mut ml = MainLoop::new().unwrap();
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg("echo Hello; echo World >&2; exit 3");
+    let o = Rc::new((RefCell::new(vec!()), RefCell::new(vec!()), Cell::new(None)));
+    let (o1, o2) = (o.clone(), o.clone());
+    call_child(&mut cmd, move |pipe, data| {
+        let v = if pipe == Pipe::Stdout { &o1.0 } else { &o1.1 };
+        v.borrow_mut().extend_from_slice(data);
+    }, move |s| {
+        o2.2.set(Some(s.unwrap()));
+        crate::terminate();
+    }).unwrap();
+    ml.run();
+    assert_eq!(&*o.0.borrow(), b"Hello\n");
+    assert_eq!(&*o.1.borrow(), b"World\n");
+    assert_eq!(o.2.get().unwrap().code(), Some(3));
+}
+
+#[test]
+fn child_no_main_loop_test() {
+    let path = std::env::temp_dir().join(format!("thin_main_loop_child_{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut cmd = Command::new("touch");
+    cmd.arg(&path);
+    let r = call_child(&mut cmd, |_, _| {}, |_| {});
+    assert!(matches!(r, Err(MainLoopError::NoMainLoop)));
+    assert!(!path.exists());
+}
@@ -1,106 +1,299 @@
-use std::cell::RefCell;
-use std::collections::VecDeque;
-use crate::{CbKind, CbId, MainLoopError};
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, BTreeMap, VecDeque};
+use crate::{CbKind, CbId, CbHandle, MainLoopError, IODirection, IOEvent, Priority};
 use std::time::{Instant, Duration};
-use std::thread;
-use crate::mainloop::SendFnOnce;
+use crate::mainloop::{SendFnOnce, catch_panic};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use boxfnonce::SendBoxFnOnce;
 
+#[cfg(not(unix))]
+use std::thread;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::io::{Read, Write};
+
 struct Data<'a> {
-    id: CbId,
     next: Instant,
+    priority: Priority,
+    kind: CbKind<'a>,
+}
+
+// Ordered by deadline, then by insertion order. Cancelled callbacks stay
+// in the heap until they are popped or the heap is compacted.
+type HeapEntry = Reverse<(Instant, u64, CbId)>;
+
+// Callbacks that are due, ordered by priority, then by insertion order.
+type ReadyEntry = Reverse<(Priority, u64, CbId)>;
+
+struct IOData<'a> {
+    handle: CbHandle,
+    direction: IODirection,
+    priority: Priority,
     kind: CbKind<'a>,
 }
 
 struct TSender {
+    #[cfg(unix)]
+    wake: UnixStream,
+    #[cfg(not(unix))]
     thread: thread::Thread,
     sender: Sender<SendBoxFnOnce<'static, ()>>,
 }
 
 impl SendFnOnce for TSender {
     fn send(&self, f: SendBoxFnOnce<'static, ()>) -> Result<(), MainLoopError> {
-        self.sender.send(f).map_err(|e| MainLoopError::Other(e.into()))?;
+        self.sender.send(f).map_err(|_| MainLoopError::ChannelClosed)?;
+        // If the socket is full, there is already a wakeup pending.
+        #[cfg(unix)]
+        let _ = (&self.wake).write(&[1]);
+        #[cfg(not(unix))]
         self.thread.unpark();
         Ok(())
     }
 }
 
 pub struct Backend<'a> {
-    data: RefCell<VecDeque<Data<'a>>>,
+    heap: RefCell<BinaryHeap<HeapEntry>>,
+    ready: RefCell<BinaryHeap<ReadyEntry>>,
+    data: RefCell<HashMap<CbId, Data<'a>>>,
+    seq: Cell<u64>,
+    io: RefCell<BTreeMap<CbId, IOData<'a>>>,
+    idle: RefCell<VecDeque<(CbId, CbKind<'a>)>>,
+    // Alternates, so callbacks that are always due cannot starve I/O and other threads
+    poll_turn: Cell<bool>,
     recv: Receiver<SendBoxFnOnce<'static, ()>>,
+    #[cfg(unix)]
+    wake: UnixStream,
+}
+
+#[cfg(unix)]
+fn dir_to_poll(d: IODirection) -> libc::c_short {
+    match d {
+        IODirection::None => 0,
+        IODirection::Read => libc::POLLIN | libc::POLLPRI,
+        IODirection::Write => libc::POLLOUT,
+        IODirection::Both => libc::POLLIN | libc::POLLPRI | libc::POLLOUT,
+    }
+}
+
+#[cfg(unix)]
+fn poll_to_event(revents: libc::c_short) -> Result<IOEvent, std::io::Error> {
+    if revents & libc::POLLNVAL != 0 { return Err(std::io::Error::from_raw_os_error(libc::EBADF)) };
+    Ok(IOEvent {
+        readable: revents & libc::POLLIN != 0,
+        writable: revents & libc::POLLOUT != 0,
+        hangup: revents & libc::POLLHUP != 0,
+        error: revents & libc::POLLERR != 0,
+        priority: revents & libc::POLLPRI != 0,
+    })
 }
 
 impl<'a> Backend<'a> {
-    pub (crate) fn new() -> Result<(Self, Box<SendFnOnce>), MainLoopError> {
+    pub (crate) fn new() -> Result<(Self, Box<dyn SendFnOnce>), MainLoopError> {
         let (tx, rx) = channel();
-        let be = Backend { recv: rx, data: Default::default() };
-        let sender = TSender { thread: thread::current(), sender: tx };
+        #[cfg(unix)]
+        let (wake, wake_tx) = {
+            let (a, b) = UnixStream::pair().map_err(|e| MainLoopError::Other(e.into()))?;
+            a.set_nonblocking(true).map_err(|e| MainLoopError::Other(e.into()))?;
+            b.set_nonblocking(true).map_err(|e| MainLoopError::Other(e.into()))?;
+            (a, b)
+        };
+        let be = Backend {
+            recv: rx,
+            heap: Default::default(),
+            ready: Default::default(),
+            data: Default::default(),
+            seq: Cell::new(0),
+            io: Default::default(),
+            idle: Default::default(),
+            poll_turn: Cell::new(false),
+            #[cfg(unix)]
+            wake,
+        };
+        let sender = TSender {
+            #[cfg(unix)]
+            wake: wake_tx,
+            #[cfg(not(unix))]
+            thread: thread::current(),
+            sender: tx
+        };
         Ok((be, Box::new(sender)))
     }
 
-    pub fn run_one(&self, wait: bool) -> bool {
+    // Removes cancelled callbacks from the top of the heap, and returns the next deadline.
+    fn peek(&self) -> Option<(Instant, CbId)> {
+        let mut h = self.heap.borrow_mut();
+        let d = self.data.borrow();
+        while let Some(Reverse((next, _, id))) = h.peek() {
+            if d.contains_key(id) { return Some((*next, *id)) };
+            h.pop();
+        }
+        None
+    }
+
+    // Moves all callbacks that are due to the ready queue, and returns the one to run first.
+    fn pop_ready(&self, now: Instant) -> Option<(CbId, Data<'a>)> {
+        let mut h = self.heap.borrow_mut();
+        let mut r = self.ready.borrow_mut();
         let mut d = self.data.borrow_mut();
-        let mut item = d.pop_front();
-        let mut next: Option<Instant> = item.as_ref().map(|i| i.next);
-        let now = Instant::now();
-        if let Some(n) = next {
-            if n > now {
-                d.push_front(item.take().unwrap());
-            } else {
-                next = d.get(0).map(|i| i.next);
+        while let Some(Reverse((next, seq, id))) = h.peek().copied() {
+            if next > now { break; }
+            h.pop();
+            if let Some(data) = d.get(&id) { r.push(Reverse((data.priority, seq, id))); }
+        }
+        while let Some(Reverse((_, _, id))) = r.pop() {
+            if let Some(data) = d.remove(&id) { return Some((id, data)) };
+        }
+        None
+    }
+
+    pub fn run_one(&self, wait: bool) -> bool {
+        if self.poll_turn.replace(!self.poll_turn.get()) {
+            if self.wait(Some(Duration::from_secs(0))) { return true; }
+            if let Ok(cb) = self.recv.try_recv() {
+                catch_panic(None, || cb.call(), ());
+                return true;
             }
         }
-        drop(d);
+
+        let now = Instant::now();
+        let item = self.pop_ready(now);
+        let next = self.peek().map(|(n, _)| n);
 
         if item.is_none() {
             if let Ok(cb) = self.recv.try_recv() {
-                cb.call();
+                catch_panic(None, || cb.call(), ());
                 return true;
             }
         }
 
-        if let Some(mut item) = item {
-            if item.kind.call_mut(None) {
+        if let Some((id, mut item)) = item {
+            if item.kind.call_mut(id, None) {
                 // Remain on the main loop
                 item.next += item.kind.duration().unwrap();
-                self.push_internal(item);
-            } else { item.kind.post_call_mut() }
+                self.push_internal(id, item);
+            } else { item.kind.post_call_mut(id) }
             true
-        } else if wait {
-            if let Some(next) = next {
-                thread::park_timeout(next - now);
-            } else {
-                thread::park();
+        } else {
+            let has_idle = !self.idle.borrow().is_empty();
+            let timeout = if !wait || has_idle { Some(Duration::from_secs(0)) } else { next.map(|n| n - now) };
+            if self.wait(timeout) { return true; }
+            if let Ok(cb) = self.recv.try_recv() {
+                catch_panic(None, || cb.call(), ());
+                return true;
             }
-            false
+            self.run_idle()
+        }
+    }
+
+    fn run_idle(&self) -> bool {
+        let item = self.idle.borrow_mut().pop_front();
+        if let Some((id, mut kind)) = item {
+            if kind.call_mut(id, None) {
+                self.idle.borrow_mut().push_back((id, kind));
+            } else { kind.post_call_mut(id) }
+            true
         } else { false }
     }
 
-    fn push_internal(&self, item: Data<'a>) {
+    // Waits for I/O or a thread wakeup. Returns true if an I/O callback was called.
+    #[cfg(unix)]
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        let mut fds = vec!(libc::pollfd { fd: self.wake.as_raw_fd(), events: libc::POLLIN, revents: 0 });
+        let mut ids = vec!();
+        for (id, io) in self.io.borrow().iter() {
+            fds.push(libc::pollfd { fd: io.handle.0, events: dir_to_poll(io.direction), revents: 0 });
+            ids.push((io.priority, *id));
+        }
+
+        let timeout = timeout.map(|t| {
+            // Round upwards, so we don't wake up just before the timer is due
+            let ms = t.as_secs().saturating_mul(1000) + u64::from(t.subsec_nanos()).div_ceil(1_000_000);
+            if ms > libc::c_int::MAX as u64 { libc::c_int::MAX } else { ms as libc::c_int }
+        }).unwrap_or(-1);
+        let r = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if r <= 0 { return false; }
+
+        if fds[0].revents != 0 {
+            let mut buf = [0u8; 64];
+            while let Ok(n) = (&self.wake).read(&mut buf) { if n == 0 { break; } }
+        }
+
+        let mut ready: Vec<_> = fds[1..].iter().zip(ids)
+            .filter(|(fd, _)| fd.revents != 0)
+            .map(|(fd, (priority, id))| (priority, id, fd.revents))
+            .collect();
+        ready.sort_by_key(|x| x.0);
+
+        let mut called = false;
+        for (_, id, revents) in ready {
+            let io = self.io.borrow_mut().remove(&id);
+            if let Some(mut io) = io {
+                called = true;
+                let dir = poll_to_event(revents);
+                if io.kind.call_mut(id, Some(dir)) {
+                    self.io.borrow_mut().insert(id, io);
+                } else { io.kind.post_call_mut(id) }
+            }
+        }
+        called
+    }
+
+    #[cfg(not(unix))]
+    fn wait(&self, timeout: Option<Duration>) -> bool {
+        if let Some(t) = timeout {
+            if t > Duration::from_secs(0) { thread::park_timeout(t) }
+        } else {
+            thread::park();
+        }
+        false
+    }
+
+    fn push_internal(&self, id: CbId, item: Data<'a>) {
+        let seq = self.seq.get();
+        self.seq.set(seq + 1);
+        let mut h = self.heap.borrow_mut();
         let mut d = self.data.borrow_mut();
-        let mut i = 0;
-        while let Some(x) = d.get(i) {
-            if x.next > item.next { break; } else { i += 1; }
+        // Compact the heap if it's mostly cancelled callbacks
+        if h.len() > 64 && h.len() > 2 * d.len() {
+            h.retain(|Reverse((_, _, id))| d.contains_key(id));
         }
-        d.insert(i, item);
+        h.push(Reverse((item.next, seq, id)));
+        d.insert(id, item);
+    }
+
+    pub (crate) fn set_io_direction(&self, id: CbId, dir: IODirection) -> bool {
+        self.io.borrow_mut().get_mut(&id).map(|io| { io.direction = dir; }).is_some()
     }
 
-    pub (crate) fn push(&self, id: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
-        if cb.handle().is_some() { return Err(MainLoopError::Unsupported) };
+    pub (crate) fn push(&self, id: CbId, cb: CbKind<'a>, priority: Priority) -> Result<(), MainLoopError> {
+        if let Some((handle, direction)) = cb.handle() {
+            if cfg!(not(unix)) { return Err(MainLoopError::Unsupported) };
+            self.io.borrow_mut().insert(id, IOData { handle, direction, priority, kind: cb });
+            return Ok(());
+        }
+        if cb.is_idle() {
+            self.idle.borrow_mut().push_back((id, cb));
+            return Ok(());
+        }
 
-        self.push_internal(Data {
-            id: id,
-            next: Instant::now() + cb.duration().unwrap_or(Duration::from_secs(0)),
+        self.push_internal(id, Data {
+            next: cb.instant().unwrap_or_else(|| Instant::now() + cb.duration().unwrap_or(Duration::from_secs(0))),
+            priority,
             kind: cb
         });
         Ok(())
     }
 
     pub (crate) fn cancel(&self, id: CbId) -> Option<CbKind<'a>> {
-        let mut d = self.data.borrow_mut();
-        d.iter().position(|x| x.id == id)
-            .and_then(|idx| d.remove(idx))
-            .map(|data| data.kind)
+        if let Some(io) = self.io.borrow_mut().remove(&id) { return Some(io.kind) };
+        let mut idle = self.idle.borrow_mut();
+        if let Some(idx) = idle.iter().position(|x| x.0 == id) { return idle.remove(idx).map(|x| x.1) };
+        drop(idle);
+        self.data.borrow_mut().remove(&id).map(|data| data.kind)
     }
 }
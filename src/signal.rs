@@ -0,0 +1,139 @@
+//! Unix signals delivered through a self-pipe, for backends without native signal support.
+//!
+//! The signal handler writes a byte to every pipe registered for the signal. The read end
+//! of each pipe is an ordinary I/O callback on the main loop that registered it.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::io;
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI32, Ordering};
+use crate::{CbHandle, IOAble, IODirection, IOEvent, MainLoopError, Signal};
+use crate::unix::{Fd, cvt_ml as cvt, set_nonblocking};
+
+const SLOTS: usize = 16;
+const SIGNALS: usize = 6;
+
+// Write ends of the pipes, indexed by signal, or -1 for unused slots.
+static PIPES: [[AtomicI32; SLOTS]; SIGNALS] = [const { [const { AtomicI32::new(-1) }; SLOTS] }; SIGNALS];
+
+lazy_static! {
+    // Number of registered pipes, and the action to restore when there are none left.
+    static ref INSTALLED: Mutex<HashMap<libc::c_int, (usize, libc::sigaction)>> = Default::default();
+}
+
+fn index(s: Signal) -> usize {
+    match s {
+        Signal::Hangup => 0,
+        Signal::Interrupt => 1,
+        Signal::Terminate => 2,
+        Signal::User1 => 3,
+        Signal::User2 => 4,
+        Signal::WindowChange => 5,
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "emscripten", target_os = "redox", target_os = "dragonfly", target_os = "hurd", target_os = "l4re"))]
+unsafe fn errno() -> *mut libc::c_int { libc::__errno_location() }
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd", target_os = "cygwin"))]
+unsafe fn errno() -> *mut libc::c_int { libc::__errno() }
+#[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+unsafe fn errno() -> *mut libc::c_int { libc::__error() }
+#[cfg(any(target_os = "solaris", target_os = "illumos"))]
+unsafe fn errno() -> *mut libc::c_int { libc::___errno() }
+#[cfg(target_os = "haiku")]
+unsafe fn errno() -> *mut libc::c_int { libc::_errnop() }
+#[cfg(target_os = "aix")]
+unsafe fn errno() -> *mut libc::c_int { libc::_Errno() }
+
+extern "C" fn handler(signum: libc::c_int) {
+    let s = match signum {
+        libc::SIGHUP => Signal::Hangup,
+        libc::SIGINT => Signal::Interrupt,
+        libc::SIGTERM => Signal::Terminate,
+        libc::SIGUSR1 => Signal::User1,
+        libc::SIGUSR2 => Signal::User2,
+        libc::SIGWINCH => Signal::WindowChange,
+        _ => return,
+    };
+    // Failed writes must not change errno for the code that was interrupted
+    let saved = unsafe { *errno() };
+    for slot in &PIPES[index(s)] {
+        let fd = slot.load(Ordering::SeqCst);
+        // If the pipe is full, a wakeup is already pending.
+        if fd >= 0 { unsafe { libc::write(fd, &1u8 as *const _ as *const _, 1); } }
+    }
+    unsafe { *errno() = saved };
+}
+
+fn pipe() -> Result<(Fd, Fd), MainLoopError> {
+    let mut fds = [0; 2];
+    cvt(unsafe { libc::pipe(fds.as_mut_ptr()) })?;
+    let fds = (Fd(fds[0]), Fd(fds[1]));
+    for fd in &[fds.0 .0, fds.1 .0] {
+        unsafe {
+            cvt(libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC))?;
+        }
+        set_nonblocking(*fd)?;
+    }
+    Ok(fds)
+}
+
+/// The main loop's end of a signal callback.
+pub (crate) struct SignalPipe<F> {
+    signal: Signal,
+    slot: usize,
+    read: Fd,
+    // Closed after the slot is cleared
+    write: Fd,
+    f: F,
+}
+
+impl<F: FnMut() -> bool> SignalPipe<F> {
+    pub (crate) fn new(signal: Signal, f: F) -> Result<Self, MainLoopError> {
+        let (read, write) = pipe()?;
+        let mut installed = INSTALLED.lock().unwrap();
+        let slots = &PIPES[index(signal)];
+        let slot = slots.iter().position(|s| s.load(Ordering::SeqCst) < 0)
+            .ok_or_else(|| MainLoopError::Other("Too many callbacks for this signal".into()))?;
+        let signum = signal.signum();
+        if let Entry::Vacant(e) = installed.entry(signum) {
+            unsafe {
+                let mut action: libc::sigaction = mem::zeroed();
+                action.sa_sigaction = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut old: libc::sigaction = mem::zeroed();
+                cvt(libc::sigaction(signum, &action, &mut old))?;
+                e.insert((0, old));
+            }
+        }
+        installed.get_mut(&signum).unwrap().0 += 1;
+        slots[slot].store(write.0, Ordering::SeqCst);
+        Ok(SignalPipe { signal, slot, read, write, f })
+    }
+}
+
+impl<F> Drop for SignalPipe<F> {
+    fn drop(&mut self) {
+        let mut installed = INSTALLED.lock().unwrap();
+        PIPES[index(self.signal)][self.slot].store(-1, Ordering::SeqCst);
+        let signum = self.signal.signum();
+        let remove = installed.get_mut(&signum).map(|x| { x.0 -= 1; x.0 == 0 }).unwrap_or(false);
+        if remove {
+            let (_, old) = installed.remove(&signum).unwrap();
+            unsafe { libc::sigaction(signum, &old, std::ptr::null_mut()) };
+        }
+    }
+}
+
+impl<F: FnMut() -> bool> IOAble for SignalPipe<F> {
+    fn handle(&self) -> CbHandle { CbHandle(self.read.0) }
+    fn direction(&self) -> IODirection { IODirection::Read }
+    fn on_rw(&mut self, _: Result<IOEvent, io::Error>) -> bool {
+        let mut buf = [0u8; 64];
+        while unsafe { libc::read(self.read.0, buf.as_mut_ptr() as *mut _, buf.len()) } > 0 {}
+        (self.f)()
+    }
+}
@@ -0,0 +1,29 @@
+//! Small helpers for the raw file descriptors used by the unix backends and futures.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use crate::MainLoopError;
+
+pub (crate) fn cvt(r: libc::c_int) -> io::Result<libc::c_int> {
+    if r < 0 { Err(io::Error::last_os_error()) } else { Ok(r) }
+}
+
+// For code that reports OS errors through the main loop API.
+pub (crate) fn cvt_ml(r: libc::c_int) -> Result<libc::c_int, MainLoopError> {
+    cvt(r).map_err(|e| MainLoopError::Other(e.into()))
+}
+
+// Closes the file descriptor when dropped.
+pub (crate) struct Fd(pub RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) { unsafe { libc::close(self.0); } }
+}
+
+pub (crate) fn set_nonblocking(fd: RawFd) -> Result<(), MainLoopError> {
+    unsafe {
+        let flags = cvt_ml(libc::fcntl(fd, libc::F_GETFL))?;
+        cvt_ml(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK))?;
+    }
+    Ok(())
+}
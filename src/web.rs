@@ -16,7 +16,7 @@ pub (crate) fn call_internal(cb: CbKind<'static>) -> Result<CbId, MainLoopError>
             Promise::resolve(&JsValue::TRUE).then(&id);
             id.forget()
         }
-        _ => unimplemented!(),
+        _ => return Err(MainLoopError::Unsupported),
     }
     Ok(CbId())
 }
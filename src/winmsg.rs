@@ -1,9 +1,9 @@
-use crate::{CbKind, CbId, MainLoopError, IODirection};
+use crate::{CbKind, CbId, MainLoopError, IODirection, IOEvent, Priority};
 use crate::mainloop::{SendFnOnce, ffi_cb_wrapper};
 use winapi;
 use std::{mem, ptr};
 use std::sync::{Once, Arc};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::cell::RefCell;
 use boxfnonce::SendBoxFnOnce;
 
@@ -26,18 +26,19 @@ struct BeInternal<'a> {
     wnd: Arc<OwnedHwnd>,
     cb_map: RefCell<HashMap<CbId, CbKind<'a>>>,
     socket_map: RefCell<HashMap<usize, CbId>>,
+    idle: RefCell<VecDeque<CbId>>,
 }
 
 impl<'a> BeInternal<'a> {
-    fn call_data(&self, cbid: CbId, dir: Option<Result<IODirection, std::io::Error>>) -> bool {
+    fn call_data(&self, cbid: CbId, dir: Option<Result<IOEvent, std::io::Error>>) -> bool {
         let kind = self.cb_map.borrow_mut().remove(&cbid);
         if let Some(mut kind) = kind {
-            if kind.call_mut(dir) {
+            if kind.call_mut(cbid, dir) {
                 self.cb_map.borrow_mut().insert(cbid, kind);
                 return true;
             }
             self.remove(cbid, &kind);
-            kind.post_call_mut();
+            kind.post_call_mut(cbid);
         }
         false
     }
@@ -62,12 +63,24 @@ impl SendFnOnce for Arc<OwnedHwnd> {
         let cb = CbKind::Asap(f.into());
         let x = Box::into_raw(Box::new(cb));
         unsafe {
-            winuser::PostMessageA(self.0, WM_CALL_THREAD, x as usize, 0);
+            if winuser::PostMessageA(self.0, WM_CALL_THREAD, x as usize, 0) == 0 {
+                drop(Box::from_raw(x));
+                return Err(MainLoopError::ChannelClosed);
+            }
         }
         Ok(())
     }
 }
 
+fn dir_to_events(d: IODirection) -> i32 {
+    match d {
+        IODirection::None => 0,
+        IODirection::Read => winsock2::FD_READ | winsock2::FD_OOB,
+        IODirection::Write => winsock2::FD_WRITE,
+        IODirection::Both => winsock2::FD_READ | winsock2::FD_OOB | winsock2::FD_WRITE,
+    } + winsock2::FD_CLOSE
+}
+
 const WM_CALL_ASAP: u32 = winuser::WM_USER + 10;
 const WM_SOCKET: u32 = winuser::WM_USER + 11;
 const WM_CALL_THREAD: u32 = winuser::WM_USER + 12;
@@ -102,11 +115,18 @@ unsafe extern "system" fn wnd_callback(wnd: HWND, msg: u32, wparam: usize, lpara
         match msg {
             WM_SOCKET => {
                 // println!("WM_Socket: {} {}", wparam, lparam);
-                let dir = match (lparam as i32) & (winsock2::FD_READ | winsock2::FD_WRITE) {
-                    0 => Ok(IODirection::None),
-                    winsock2::FD_READ => Ok(IODirection::Read),
-                    winsock2::FD_WRITE => Ok(IODirection::Write),
-                    _ => Ok(IODirection::Both),
+                let events = (lparam as i32) & 0xffff;
+                let error = ((lparam as i32) >> 16) & 0xffff;
+                let dir = if error != 0 && events & winsock2::FD_CLOSE == 0 {
+                    Err(std::io::Error::from_raw_os_error(error))
+                } else {
+                    Ok(IOEvent {
+                        readable: events & winsock2::FD_READ != 0,
+                        writable: events & winsock2::FD_WRITE != 0,
+                        hangup: events & winsock2::FD_CLOSE != 0,
+                        error: error != 0,
+                        priority: events & winsock2::FD_OOB != 0,
+                    })
                 };
                 let cbid = *be.socket_map.borrow().get(&wparam).unwrap();
                 if !be.call_data(cbid, Some(dir)) {
@@ -119,9 +139,9 @@ unsafe extern "system" fn wnd_callback(wnd: HWND, msg: u32, wparam: usize, lpara
                 be.call_data(cbid, None);
             },
             WM_CALL_THREAD => {
-                let mut kind: Box<CbKind<'static>> = Box::from_raw(wparam as *mut _);
-                assert!(!kind.call_mut(None));
-                kind.post_call_mut();
+                let kind: Box<CbKind<'static>> = Box::from_raw(wparam as *mut _);
+                // Sent from another thread, so there is no CbId
+                if let CbKind::Asap(f) = *kind { f.call() } else { unreachable!() }
             }
             _ => unreachable!(),
         };
@@ -136,7 +156,7 @@ impl<'a> Drop for Backend<'a> {
 }
 
 impl<'a> Backend<'a> {
-    pub (crate) fn new() -> Result<(Self, Box<SendFnOnce>), MainLoopError> {
+    pub (crate) fn new() -> Result<(Self, Box<dyn SendFnOnce>), MainLoopError> {
         ensure_window_class();
         //println!("call CreateWindowExA");
         let wnd = unsafe { winuser::CreateWindowExA(
@@ -160,7 +180,8 @@ impl<'a> Backend<'a> {
         let be = Box::new(BeInternal {
             wnd: ownd.clone(),
             cb_map: Default::default(),
-            socket_map: Default::default()
+            socket_map: Default::default(),
+            idle: Default::default(),
         });
         unsafe {
             let be_ptr: &BeInternal = &be;
@@ -177,6 +198,8 @@ impl<'a> Backend<'a> {
                 winuser::TranslateMessage(&msg);
                 winuser::DispatchMessageW(&msg);
                 true
+            } else if self.run_idle() {
+                true
             } else if wait {
                 winuser::WaitMessage();
                 false
@@ -184,6 +207,14 @@ impl<'a> Backend<'a> {
         }
     }
 
+    fn run_idle(&self) -> bool {
+        let cbid = self.0.idle.borrow_mut().pop_front();
+        if let Some(cbid) = cbid {
+            if self.0.call_data(cbid, None) { self.0.idle.borrow_mut().push_back(cbid); }
+            true
+        } else { false }
+    }
+
     pub (crate) fn cancel(&self, cbid: CbId) -> Option<CbKind<'a>> {
         let z = self.0.cb_map.borrow_mut().remove(&cbid);
         if let Some(cb) = z.as_ref() {
@@ -192,22 +223,30 @@ impl<'a> Backend<'a> {
         z
     }
 
-    pub (crate) fn push(&self, cbid: CbId, cb: CbKind<'a>) -> Result<(), MainLoopError> {
+    pub (crate) fn set_io_direction(&self, cbid: CbId, dir: IODirection) -> bool {
+        let cb_map = self.0.cb_map.borrow();
+        if let Some((socket, _)) = cb_map.get(&cbid).and_then(|k| k.handle()) {
+            unsafe { winsock2::WSAAsyncSelect(socket.0 as usize, self.0.wnd.0, WM_SOCKET, dir_to_events(dir)) };
+            true
+        } else { false }
+    }
+
+    // Window messages have no priorities, so the priority is ignored.
+    pub (crate) fn push(&self, cbid: CbId, cb: CbKind<'a>, priority: Priority) -> Result<(), MainLoopError> {
         assert!(cbid.0 <= std::usize::MAX as u64);
         let cbu = cbid.0 as usize;
         let wnd = self.0.wnd.0;
         if let Some((socket, direction)) = cb.handle() {
-            let events = match direction {
-                IODirection::None => 0,
-                IODirection::Read => winsock2::FD_READ,
-                IODirection::Write => winsock2::FD_WRITE,
-                IODirection::Both => winsock2::FD_READ | winsock2::FD_WRITE,
-            } + winsock2::FD_CLOSE;
             let sock = socket.0 as usize;
-            unsafe { winsock2::WSAAsyncSelect(sock, wnd, WM_SOCKET, events) };
+            if unsafe { winsock2::WSAAsyncSelect(sock, wnd, WM_SOCKET, dir_to_events(direction)) } == winsock2::SOCKET_ERROR {
+                let e = std::io::Error::from_raw_os_error(unsafe { winsock2::WSAGetLastError() });
+                return Err(MainLoopError::IoRegistration(e));
+            }
             self.0.socket_map.borrow_mut().insert(sock, cbid);
         } else if let Some(d) = cb.duration_millis()? {
             unsafe { winuser::SetTimer(wnd, cbu, d, None); }
+        } else if cb.is_idle() {
+            self.0.idle.borrow_mut().push_back(cbid);
         } else {
             unsafe { winuser::PostMessageW(wnd, WM_CALL_ASAP, cbu, 0); }
         };